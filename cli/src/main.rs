@@ -0,0 +1,245 @@
+//! Control a running choosy server from the shell.
+//!
+//! Output is meant for pipes: `choosy-cli search star | fzf | choosy-cli play`.
+
+use anyhow::{bail, Context};
+use choosy_protocol as proto;
+use std::io::BufRead;
+use structopt::StructOpt;
+
+#[derive(structopt::StructOpt, Debug)]
+#[structopt(
+    name = "choosy-cli",
+    about = "Control a running choosy server",
+    no_version
+)]
+struct Opt {
+    /// Where choosy listens
+    #[structopt(long, env = "CHOOSY_URL", default_value = "http://localhost:8000")]
+    url: String,
+
+    /// One of the server's `auth.tokens`, if it wants one
+    #[structopt(long, env = "CHOOSY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(structopt::StructOpt, Debug)]
+enum Command {
+    /// Print files matching all the words, one per line
+    Search { words: Vec<String> },
+    /// Play a file; without one, play the first line of stdin
+    Play { filename: Option<String> },
+    /// Play files after the current one; without any, queue the lines of stdin
+    Queue { filenames: Vec<String> },
+    /// Pause playback
+    Pause {
+        /// Resume instead
+        #[structopt(long, conflicts_with = "toggle")]
+        resume: bool,
+        /// Pause if playing, resume if paused
+        #[structopt(long)]
+        toggle: bool,
+    },
+    /// Seek to a position like `90` or `1:30`, or by an amount like `+30` or `-10`
+    #[structopt(setting = structopt::clap::AppSettings::AllowLeadingHyphen)]
+    Seek {
+        #[structopt(parse(try_from_str = parse_seek))]
+        position: proto::SeekCommand,
+    },
+    /// Print what is playing, as tab-separated `state position duration filename`
+    Status {
+        /// Print the server's response as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+// Seconds, optionally as `[[h:]m:]s`.
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    let mut seconds = 0.0;
+    for part in s.split(':') {
+        let n: f64 = part.parse().map_err(|_| format!("not a time: {:?}", s))?;
+        seconds = seconds * 60.0 + n;
+    }
+    Ok(seconds)
+}
+
+fn parse_seek(s: &str) -> Result<proto::SeekCommand, String> {
+    let command = if let Some(rest) = s.strip_prefix('+') {
+        proto::SeekCommand {
+            seconds: parse_seconds(rest)?,
+            relative: true,
+        }
+    } else if let Some(rest) = s.strip_prefix('-') {
+        proto::SeekCommand {
+            seconds: -parse_seconds(rest)?,
+            relative: true,
+        }
+    } else {
+        proto::SeekCommand {
+            seconds: parse_seconds(s)?,
+            relative: false,
+        }
+    };
+    Ok(command)
+}
+
+struct Client {
+    agent: ureq::Agent,
+    base: String,
+    token: Option<String>,
+}
+
+impl Client {
+    fn request(&self, method: &str, endpoint: &str) -> ureq::Request {
+        let url = format!(
+            "{}{}/{}",
+            self.base.trim_end_matches('/'),
+            proto::API_PREFIX,
+            endpoint
+        );
+        let request = self.agent.request(method, &url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, anyhow::Error> {
+        let mut request = self.request("GET", endpoint);
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        let response = check(request.call())?;
+        let value = response.into_json().context("bad response from server")?;
+        Ok(value)
+    }
+
+    fn post<T: serde::Serialize>(&self, endpoint: &str, body: &T) -> Result<(), anyhow::Error> {
+        check(self.request("POST", endpoint).send_json(body))?;
+        Ok(())
+    }
+}
+
+// Turn an error status into the server's explanation.
+fn check(result: Result<ureq::Response, ureq::Error>) -> Result<ureq::Response, anyhow::Error> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => {
+            match response.into_json::<proto::ErrorResponse>() {
+                Ok(error) => bail!("{:?}: {}", error.code, error.message),
+                Err(_) => bail!("server error: HTTP {}", status),
+            }
+        }
+        Err(error) => Err(error).context("cannot reach choosy"),
+    }
+}
+
+fn stdin_lines() -> Result<Vec<String>, anyhow::Error> {
+    let stdin = std::io::stdin();
+    let mut lines = Vec::new();
+    for line in stdin.lock().lines() {
+        let line = line.context("error reading stdin")?;
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let opt = Opt::from_args();
+    let client = Client {
+        agent: ureq::agent(),
+        base: opt.url,
+        token: opt.token,
+    };
+    match opt.command {
+        Command::Search { words } => {
+            let response: proto::SearchResponse =
+                client.get("search", &[("q", &words.join(" "))])?;
+            for item in response.items {
+                println!("{}", item.filename);
+            }
+        }
+        Command::Play { filename } => {
+            let filename = match filename {
+                Some(filename) => filename,
+                None => match stdin_lines()?.into_iter().next() {
+                    Some(filename) => filename,
+                    None => bail!("nothing to play"),
+                },
+            };
+            client.post("play", &proto::PlayCommand { filename })?;
+        }
+        Command::Queue { filenames } => {
+            let filenames = if filenames.is_empty() {
+                stdin_lines()?
+            } else {
+                filenames
+            };
+            for filename in filenames {
+                client.post("queue", &proto::QueueCommand { filename })?;
+            }
+        }
+        Command::Pause { resume, toggle } => {
+            let pause = if toggle { None } else { Some(!resume) };
+            client.post("pause", &proto::PauseCommand { pause })?;
+        }
+        Command::Seek { position } => {
+            client.post("seek", &position)?;
+        }
+        Command::Status { json } => {
+            let response: proto::StatusResponse = client.get("status", &[])?;
+            if json {
+                println!("{}", serde_json::to_string(&response)?);
+                return Ok(());
+            }
+            match response.playing {
+                None => println!("stopped"),
+                Some(now) => {
+                    let seconds = |s: Option<f64>| match s {
+                        Some(s) => format!("{:.1}", s),
+                        None => "-".to_string(),
+                    };
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        if now.paused { "paused" } else { "playing" },
+                        seconds(now.position),
+                        seconds(now.duration),
+                        now.filename
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek() {
+        let seek = |s| {
+            let command = parse_seek(s).unwrap();
+            (command.seconds, command.relative)
+        };
+        assert_eq!(seek("90"), (90.0, false));
+        assert_eq!(seek("1:30"), (90.0, false));
+        assert_eq!(seek("1:00:05"), (3605.0, false));
+        assert_eq!(seek("+30"), (30.0, true));
+        assert_eq!(seek("-1:00"), (-60.0, true));
+        assert_eq!(seek("+2.5"), (2.5, true));
+        assert!(parse_seek("soon").is_err());
+        assert!(parse_seek("1::2").is_err());
+    }
+}
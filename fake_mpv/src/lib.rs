@@ -0,0 +1,34 @@
+//! Where to find fake mpv, for tests in other packages.
+//!
+//! Cargo only builds binaries for the tests of their own package, so this builds them, and asks cargo where they went.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+static FAKE_MPV: once_cell::sync::Lazy<PathBuf> =
+    once_cell::sync::Lazy::new(|| build_bin("fake_mpv", "fake-mpv"));
+
+/// Path to the fake mpv executable, built on first use.
+pub fn path() -> &'static Path {
+    &FAKE_MPV
+}
+
+/// Build binary `bin` of `package` in this workspace, returning the path to the executable.
+/// Panics on failure, like a test would.
+pub fn build_bin(package: &str, bin: &str) -> PathBuf {
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "--message-format=json"])
+        .arg(format!("--package={}", package))
+        .arg(format!("--bin={}", bin))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|error| panic!("must run cargo to build {}: {}", bin, error));
+    assert!(output.status.success(), "building {} failed", bin);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["target"]["name"] == bin)
+        .find_map(|message| message["executable"].as_str().map(PathBuf::from))
+        .unwrap_or_else(|| panic!("cargo must say where {} was built", bin))
+}
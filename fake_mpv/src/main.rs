@@ -0,0 +1,346 @@
+// A scriptable stand-in for mpv, for tests that must not need a display.
+//
+// Speaks the JSON IPC protocol on the fd given with `--input-ipc-client=fd://N`, and ignores all other options.
+// With `--input-ipc-server=PATH` instead, it listens on a Unix socket and serves one client at a time, until killed.
+// Files given after `--` get a `start-file` event each, when a client connects.
+//
+// Commands are answered from a small set of built-in behaviors:
+//
+// - `client_name`: `"fake-mpv"`
+// - `get_property NAME`, `set_property NAME VALUE`: a property map, preloaded with `pause`, and with `path`, `time-pos` and `duration` while a file is loaded
+// - `observe_property ID NAME`: success, then a `property-change` event for `NAME`, now and whenever a command changes it
+// - `loadfile URL`, also with named arguments: sets `path` and sends `start-file`
+// - `seek SECONDS [relative|absolute]`: changes `time-pos`
+// - `cycle NAME`: flips a boolean property
+// - `quit [CODE]`: success, then exit
+// - `hang`: never answered, to test cancellation
+// - anything else: error `invalid parameter`
+//
+// The environment variable `FAKE_MPV_SCRIPT` can hold a JSON array of steps, run in order alongside answering commands:
+//
+// - `{"send": VALUE}`: write `VALUE` as a line on the IPC socket
+// - `{"expect": "NAME"}`: wait until a command named `NAME` has been received
+// - `{"sleep": SECONDS}`
+// - `{"exit": CODE}`: exit the process
+//
+// If `FAKE_MPV_LOG` names a file, the command line and every received IPC line are appended to it.
+//
+// SIGTERM makes the fake exit successfully, like a user quitting mpv.
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    Send(serde_json::Value),
+    Expect(String),
+    Sleep(f64),
+    Exit(i32),
+}
+
+struct Fake {
+    // The current client, if any.
+    writer: Mutex<Option<UnixStream>>,
+    log: Option<Mutex<std::fs::File>>,
+    properties: Mutex<HashMap<String, serde_json::Value>>,
+    // Ids and names from `observe_property`.
+    observed: Mutex<Vec<(u64, String)>>,
+}
+
+impl Fake {
+    fn log(&self, line: &str) {
+        if let Some(log) = &self.log {
+            let mut guard = log.lock().unwrap();
+            writeln!(guard, "{}", line).expect("cannot write log");
+        }
+    }
+
+    fn send(&self, message: &serde_json::Value) {
+        let mut line = serde_json::to_vec(message).unwrap();
+        line.push(b'\n');
+        let mut guard = self.writer.lock().unwrap();
+        if let Some(writer) = &mut *guard {
+            // The client may already have gone away, and that's its business.
+            let _ignore_error = writer.write_all(&line);
+        }
+    }
+
+    fn respond(&self, request_id: u64, result: Result<serde_json::Value, &str>) {
+        let message = match result {
+            Ok(data) => json!({"request_id": request_id, "error": "success", "data": data}),
+            Err(error) => json!({"request_id": request_id, "error": error}),
+        };
+        self.send(&message);
+    }
+
+    // Send `property-change` for the observed ones among `names`.
+    fn changed(&self, names: &[&str]) {
+        let events: Vec<serde_json::Value> = {
+            let observed = self.observed.lock().unwrap();
+            let properties = self.properties.lock().unwrap();
+            observed
+                .iter()
+                .filter(|(_id, name)| names.contains(&name.as_str()))
+                .map(|(id, name)| {
+                    let mut event = json!({"event": "property-change", "id": id, "name": name});
+                    // Like mpv, unavailable properties have no data.
+                    if let Some(value) = properties.get(name) {
+                        event["data"] = value.clone();
+                    }
+                    event
+                })
+                .collect()
+        };
+        for event in &events {
+            self.send(event);
+        }
+    }
+
+    fn handle(&self, request: &serde_json::Value) -> Option<String> {
+        let request_id = request["request_id"].as_u64().unwrap_or(0);
+        let args: Vec<serde_json::Value> = match &request["command"] {
+            serde_json::Value::Array(args) => args.clone(),
+            // Named arguments; only the ones we care about, in positional order.
+            serde_json::Value::Object(named) => ["name", "url"]
+                .iter()
+                .filter_map(|key| named.get(*key).cloned())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let name = args.first()?.as_str()?.to_string();
+        match (name.as_str(), &args[1..]) {
+            ("client_name", []) => self.respond(request_id, Ok(json!("fake-mpv"))),
+            ("get_property", [property]) => {
+                let guard = self.properties.lock().unwrap();
+                match property.as_str().and_then(|p| guard.get(p)) {
+                    Some(value) => self.respond(request_id, Ok(value.clone())),
+                    None => self.respond(request_id, Err("property unavailable")),
+                }
+            }
+            ("set_property", [property, value]) => match property.as_str() {
+                Some(property) => {
+                    {
+                        let mut guard = self.properties.lock().unwrap();
+                        guard.insert(property.to_string(), value.clone());
+                    }
+                    self.respond(request_id, Ok(serde_json::Value::Null));
+                    self.changed(&[property]);
+                }
+                None => self.respond(request_id, Err("invalid parameter")),
+            },
+            ("observe_property", [id, property]) => match (id.as_u64(), property.as_str()) {
+                (Some(id), Some(property)) => {
+                    {
+                        let mut guard = self.observed.lock().unwrap();
+                        guard.push((id, property.to_string()));
+                    }
+                    self.respond(request_id, Ok(serde_json::Value::Null));
+                    self.changed(&[property]);
+                }
+                _ => self.respond(request_id, Err("invalid parameter")),
+            },
+            ("loadfile", [url, ..]) => {
+                {
+                    let mut guard = self.properties.lock().unwrap();
+                    load(&mut guard, url.clone());
+                }
+                self.respond(request_id, Ok(serde_json::Value::Null));
+                self.send(&json!({"event": "start-file", "playlist_entry_id": 1}));
+                self.changed(&["path", "time-pos", "duration"]);
+            }
+            ("cycle", [property]) => {
+                let property = property.as_str().unwrap_or_default();
+                let cycled = {
+                    let mut guard = self.properties.lock().unwrap();
+                    match guard.get(property).and_then(|value| value.as_bool()) {
+                        Some(value) => {
+                            guard.insert(property.to_string(), json!(!value));
+                            true
+                        }
+                        None => false,
+                    }
+                };
+                if cycled {
+                    self.respond(request_id, Ok(serde_json::Value::Null));
+                    self.changed(&[property]);
+                } else {
+                    self.respond(request_id, Err("invalid parameter"));
+                }
+            }
+            ("seek", [amount, rest @ ..]) => {
+                let sought = {
+                    let mut guard = self.properties.lock().unwrap();
+                    let amount = amount.as_f64();
+                    let absolute = rest.first().and_then(|mode| mode.as_str()) == Some("absolute");
+                    let position = guard.get("time-pos").and_then(|pos| pos.as_f64());
+                    match (amount, position) {
+                        (Some(amount), Some(position)) => {
+                            let position = if absolute { amount } else { position + amount };
+                            guard.insert("time-pos".to_string(), json!(position));
+                            true
+                        }
+                        _ => false,
+                    }
+                };
+                if sought {
+                    self.respond(request_id, Ok(serde_json::Value::Null));
+                    self.changed(&["time-pos"]);
+                } else {
+                    self.respond(request_id, Err("invalid parameter"));
+                }
+            }
+            ("quit", rest) => {
+                self.respond(request_id, Ok(serde_json::Value::Null));
+                let code = rest.first().and_then(|c| c.as_i64()).unwrap_or(0);
+                std::process::exit(code as i32);
+            }
+            ("hang", _) => (),
+            _ => self.respond(request_id, Err("invalid parameter")),
+        }
+        Some(name)
+    }
+}
+
+fn load(properties: &mut HashMap<String, serde_json::Value>, path: serde_json::Value) {
+    properties.insert("path".to_string(), path);
+    properties.insert("time-pos".to_string(), json!(0.0));
+    properties.insert("duration".to_string(), json!(3600.0));
+}
+
+fn run_script(fake: &Fake, steps: Vec<Step>, seen: mpsc::Receiver<String>) {
+    for step in steps {
+        match step {
+            Step::Send(message) => fake.send(&message),
+            Step::Expect(name) => loop {
+                match seen.recv() {
+                    Ok(got) if got == name => break,
+                    Ok(_) => continue,
+                    // IPC closed while we were waiting; nothing left to do.
+                    Err(mpsc::RecvError) => return,
+                }
+            },
+            Step::Sleep(seconds) => std::thread::sleep(Duration::from_secs_f64(seconds)),
+            Step::Exit(code) => std::process::exit(code),
+        }
+    }
+}
+
+extern "C" fn exit_on_sigterm(_signal: libc::c_int) {
+    unsafe { libc::_exit(0) };
+}
+
+// Serve one client until it disconnects.
+fn serve(fake: &Fake, socket: UnixStream, files: &[String], seen: &mpsc::Sender<String>) {
+    {
+        let mut guard = fake.writer.lock().unwrap();
+        *guard = Some(socket.try_clone().expect("cannot clone IPC socket"));
+    }
+    for (index, _file) in files.iter().enumerate() {
+        fake.send(&json!({"event": "start-file", "playlist_entry_id": index + 1}));
+    }
+
+    for line in BufReader::new(socket).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        fake.log(&line);
+        let request: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        if let Some(name) = fake.handle(&request) {
+            // The script may have finished already.
+            let _ignore_error = seen.send(name);
+        }
+    }
+
+    let mut guard = fake.writer.lock().unwrap();
+    *guard = None;
+    // Like mpv, observers belong to the client.
+    fake.observed.lock().unwrap().clear();
+}
+
+fn main() {
+    unsafe {
+        libc::signal(
+            libc::SIGTERM,
+            exit_on_sigterm as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut ipc_fd = None;
+    let mut ipc_server = None;
+    let mut files = Vec::new();
+    let mut options_done = false;
+    for arg in &args {
+        if options_done {
+            files.push(arg.clone());
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(fd) = arg.strip_prefix("--input-ipc-client=fd://") {
+            ipc_fd = Some(fd.parse::<i32>().expect("bad IPC fd"));
+        } else if let Some(path) = arg.strip_prefix("--input-ipc-server=") {
+            ipc_server = Some(path.to_string());
+        }
+    }
+
+    let steps: Vec<Step> = match std::env::var("FAKE_MPV_SCRIPT") {
+        Ok(script) => serde_json::from_str(&script).expect("bad FAKE_MPV_SCRIPT"),
+        Err(_) => Vec::new(),
+    };
+    let log = std::env::var_os("FAKE_MPV_LOG").map(|path| {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("cannot open FAKE_MPV_LOG");
+        Mutex::new(file)
+    });
+
+    let mut properties = HashMap::new();
+    properties.insert("pause".to_string(), json!(false));
+    if let Some(path) = files.first() {
+        load(&mut properties, json!(path));
+    }
+    let fake = Arc::new(Fake {
+        writer: Mutex::new(None),
+        log,
+        properties: Mutex::new(properties),
+        observed: Mutex::new(Vec::new()),
+    });
+    fake.log(&json!({ "args": args }).to_string());
+
+    let (seen_sender, seen_receiver) = mpsc::channel();
+    let script = {
+        let fake = fake.clone();
+        std::thread::spawn(move || run_script(&fake, steps, seen_receiver))
+    };
+
+    match (ipc_fd, ipc_server) {
+        (Some(fd), _) => {
+            let socket = unsafe { UnixStream::from_raw_fd(fd) };
+            serve(&fake, socket, &files, &seen_sender);
+            // Like mpv, exit when the client goes away, but let the script finish first.
+            drop(seen_sender);
+            let _ignore_panic = script.join();
+        }
+        (None, Some(path)) => {
+            let listener = UnixListener::bind(&path).expect("cannot listen on IPC socket");
+            for socket in listener.incoming() {
+                let socket = socket.expect("cannot accept IPC client");
+                serve(&fake, socket, &files, &seen_sender);
+            }
+        }
+        (None, None) => {
+            panic!("fake-mpv needs --input-ipc-client=fd://N or --input-ipc-server=PATH")
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use mpv_remote::{CloseError, IPCError, MPVEvent, MPVEventKind, PropertyChange, MPV};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::time::Duration;
+
+fn fake_mpv(script: serde_json::Value) -> mpv_remote::ConfigBuilder {
+    let mut builder = MPV::builder();
+    builder
+        .fullscreen(false)
+        .executable(env!("CARGO_BIN_EXE_fake-mpv"))
+        .env_var("FAKE_MPV_SCRIPT", script.to_string());
+    builder
+}
+
+async fn next_event(events: &mut tokio::sync::broadcast::Receiver<MPVEvent>) -> MPVEvent {
+    tokio::time::timeout(Duration::from_secs(10), events.recv())
+        .await
+        .expect("timeout waiting for mpv event")
+        .expect("mpv events closed")
+}
+
+#[tokio::test]
+async fn command_and_close() {
+    let mpv = fake_mpv(json!([]))
+        .build()
+        .unwrap()
+        .play(OsStr::new("/nonexistent/video.mkv"))
+        .expect("must start fake mpv");
+
+    let name = mpv.command(json!(["client_name"])).await.unwrap();
+    assert_eq!(name, json!("fake-mpv"));
+    let path = mpv.command(json!(["get_property", "path"])).await.unwrap();
+    assert_eq!(path, json!("/nonexistent/video.mkv"));
+    match mpv.command(json!(["no_such_command"])).await {
+        Err(IPCError::FromMPV(error)) => assert_eq!(error, "invalid parameter"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    mpv.close().await.expect("close must succeed");
+}
+
+#[tokio::test]
+async fn commander_outlives_close() {
+    let mpv = fake_mpv(json!([]))
+        .build()
+        .unwrap()
+        .play(OsStr::new("video.mkv"))
+        .expect("must start fake mpv");
+
+    let commander = mpv.commander();
+    let name = commander.command(json!(["client_name"])).await.unwrap();
+    assert_eq!(name, json!("fake-mpv"));
+
+    mpv.close().await.expect("close must succeed");
+    match commander.command(json!(["client_name"])).await {
+        Err(IPCError::Disconnected) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn scripted_events() {
+    let mpv = fake_mpv(json!([
+        {"expect": "observe_property"},
+        {"send": {"event": "property-change", "id": 42, "name": "time-pos", "data": 1.5}},
+    ]))
+    .build()
+    .unwrap()
+    .play(OsStr::new("video.mkv"))
+    .expect("must start fake mpv");
+
+    let mut events = mpv.events().await;
+    mpv.command(json!(["observe_property", 42, "time-pos"]))
+        .await
+        .unwrap();
+    let expected: MPVEvent = serde_json::from_value(
+        json!({"event": "property-change", "id": 42, "name": "time-pos", "data": 1.5}),
+    )
+    .unwrap();
+    // Skip the `start-file` event, if we subscribed fast enough to see it.
+    while next_event(&mut events).await != expected {}
+
+    mpv.close().await.expect("close must succeed");
+}
+
+#[tokio::test]
+async fn observed_property_changes() {
+    let mpv = fake_mpv(json!([]))
+        .build()
+        .unwrap()
+        .play(OsStr::new("video.mkv"))
+        .expect("must start fake mpv");
+
+    let mut events = mpv.events().await;
+    mpv.command(json!(["observe_property", 1, "pause"]))
+        .await
+        .unwrap();
+    mpv.command(json!(["cycle", "pause"])).await.unwrap();
+    let mut seen = Vec::new();
+    while seen.len() < 2 {
+        if let MPVEventKind::PropertyChange(change) = next_event(&mut events).await.kind() {
+            seen.push(change.clone());
+        }
+    }
+    assert_eq!(
+        seen,
+        [
+            PropertyChange::Pause { paused: false },
+            PropertyChange::Pause { paused: true },
+        ]
+    );
+
+    mpv.close().await.expect("close must succeed");
+}
+
+#[tokio::test]
+async fn exit_cancels_pending_commands() {
+    let mpv = fake_mpv(json!([
+        {"expect": "hang"},
+        {"exit": 0},
+    ]))
+    .build()
+    .unwrap()
+    .play(OsStr::new("video.mkv"))
+    .expect("must start fake mpv");
+
+    let mut events = mpv.events().await;
+    match mpv.command(json!(["hang"])).await {
+        Err(IPCError::Disconnected) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    // The events channel closes when mpv goes away.
+    loop {
+        match events.recv().await {
+            Ok(_) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            Err(error) => panic!("unexpected error: {:?}", error),
+        }
+    }
+    mpv.close().await.expect("close must succeed");
+}
+
+#[tokio::test]
+async fn exit_status_is_reported() {
+    let mpv = fake_mpv(json!([
+        {"expect": "client_name"},
+        {"exit": 3},
+    ]))
+    .build()
+    .unwrap()
+    .play(OsStr::new("video.mkv"))
+    .expect("must start fake mpv");
+
+    let mut events = mpv.events().await;
+    mpv.command(json!(["client_name"])).await.unwrap();
+    // Don't let close race the scripted exit.
+    while events.recv().await.is_ok() {}
+    match mpv.close().await {
+        Err(CloseError::MPVExitStatus(status)) => assert_eq!(status.code(), Some(3)),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn command_after_exit() {
+    let mpv = fake_mpv(json!([
+        {"expect": "client_name"},
+        {"exit": 0},
+    ]))
+    .build()
+    .unwrap()
+    .play(OsStr::new("video.mkv"))
+    .expect("must start fake mpv");
+
+    let mut events = mpv.events().await;
+    mpv.command(json!(["client_name"])).await.unwrap();
+    while events.recv().await.is_ok() {}
+    match mpv.command(json!(["client_name"])).await {
+        Err(IPCError::Disconnected) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    mpv.close().await.expect("close must succeed");
+}
+
+#[tokio::test]
+async fn missing_executable() {
+    let result = MPV::builder()
+        .executable("/nonexistent/mpv")
+        .build()
+        .unwrap()
+        .play(OsStr::new("video.mkv"));
+    assert!(matches!(
+        result,
+        Err(mpv_remote::StartError::StartingMPV(_))
+    ));
+}
+
+#[tokio::test]
+async fn connect_to_running_mpv() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket_path = dir.path().join("mpv.socket");
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_fake-mpv"))
+        .arg(format!("--input-ipc-server={}", socket_path.display()))
+        .arg("--idle=yes")
+        .spawn()
+        .expect("must start fake mpv");
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while !socket_path.exists() {
+        if let Some(status) = child.try_wait().unwrap() {
+            panic!("fake mpv exited early: {}", status);
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "timeout waiting for fake mpv to listen"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mpv = MPV::connect(&socket_path).await.expect("must connect");
+    assert_eq!(
+        mpv.command(json!(["client_name"])).await.unwrap(),
+        json!("fake-mpv")
+    );
+    mpv.command(json!(["set_property", "pause", true]))
+        .await
+        .unwrap();
+    mpv.close().await.expect("close must succeed");
+
+    // Closing only disconnected; the same mpv is still there.
+    let mpv = MPV::connect(&socket_path).await.expect("must reconnect");
+    assert_eq!(
+        mpv.command(json!(["get_property", "pause"])).await.unwrap(),
+        json!(true)
+    );
+    mpv.close().await.expect("close must succeed");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[tokio::test]
+async fn connect_to_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let result = MPV::connect(dir.path().join("nonexistent.socket")).await;
+    assert!(matches!(result, Err(mpv_remote::ConnectError::Connect(_))));
+}
+
+#[tokio::test]
+async fn idle_then_loadfile() {
+    let dir = tempfile::tempdir().unwrap();
+    let log_path = dir.path().join("fake-mpv.log");
+    let mpv = fake_mpv(json!([]))
+        .env_var("FAKE_MPV_LOG", &log_path)
+        .build()
+        .unwrap()
+        .start_idle()
+        .expect("must start fake mpv");
+
+    let mut options = BTreeMap::new();
+    options.insert("aid".to_string(), "2".to_string());
+    mpv.loadfile(OsStr::new("/videos/first.mkv"), &options)
+        .await
+        .unwrap();
+    assert_eq!(
+        mpv.command(json!(["get_property", "path"])).await.unwrap(),
+        json!("/videos/first.mkv")
+    );
+    mpv.loadfile(OsStr::new("/videos/second.mkv"), &BTreeMap::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        mpv.command(json!(["get_property", "path"])).await.unwrap(),
+        json!("/videos/second.mkv")
+    );
+    mpv.close().await.expect("close must succeed");
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let args = lines[0]["args"].as_array().unwrap();
+    assert!(args.contains(&json!("--idle=yes")));
+    assert!(!args.contains(&json!("--")));
+    assert_eq!(lines[1]["command"]["options"], json!({"aid": "2"}));
+}
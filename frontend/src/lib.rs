@@ -2,29 +2,194 @@
 #![recursion_limit = "256"]
 
 use choosy_protocol as proto;
+use futures::channel::mpsc;
 use gloo_net::http::Request;
-use std::collections::BTreeMap;
+use route::Route;
+use std::collections::{BTreeMap, BTreeSet};
 use std::rc::Rc;
 use tracing::{error, info};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use yew::prelude::*;
 
+mod route;
+
 struct Model {
+    route: Route,
+    _route_change: gloo_events::EventListener,
     search: Rc<str>,
+    // Only when browsing.
+    directories: Vec<Rc<str>>,
     files: BTreeMap<Rc<str>, ()>,
+    // The server wants a password before it does anything.
+    need_login: bool,
+    password_input: NodeRef,
+    search_input: NodeRef,
+    // Index into `directories` and then `files` of the highlighted entry, for the keyboard.
+    selected: usize,
+    // The selection moved, and may be off screen.
+    scroll_to_selected: bool,
+    // Keyboard shortcuts, except while logging in.
+    keys: Option<gloo_events::EventListener>,
+    // Why the last thing the user did failed, until they do something else.
+    error: Option<String>,
+    // Requests go over the WebSocket while it is open, and over HTTP otherwise.
+    socket: Option<mpsc::UnboundedSender<proto::ClientMessage>>,
+    // Without the WebSocket, nothing pushes the status; ask for it instead.
+    status_poll: Option<gloo_timers::callback::Interval>,
+    next_id: u64,
+    // Results of any older search or browse are stale, and dropped.
+    latest_listing: u64,
+    // Commands sent over the WebSocket and not answered yet; other errors are for stale listings.
+    commands: BTreeSet<u64>,
+    // Waiting for the latest search or browse.
+    loading: bool,
+    // The HTTP request for the latest listing, to cancel when there is a newer one.
+    in_flight: Option<web_sys::AbortController>,
+    // Searching waits for a pause in typing.
+    search_delay: Option<gloo_timers::callback::Timeout>,
+    now_playing: Option<proto::NowPlaying>,
+    transport: NodeRef,
+    swipe_start: Option<i32>,
 }
 
+// How long typing must pause before searching.
+const SEARCH_DELAY_MILLIS: u32 = 150;
+
+// How often to ask what is playing, without the WebSocket.
+const STATUS_POLL_MILLIS: u32 = 1000;
+
+// A swipe across the whole transport bar seeks this far.
+const SWIPE_SECONDS: f64 = 300.0;
+// Anything shorter is a tap.
+const SWIPE_MIN_PIXELS: i32 = 20;
+
 enum Msg {
+    // Fetch what the route shows.
+    Load,
+    Navigate {
+        route: Route,
+    },
+    RouteChanged {
+        route: Route,
+    },
     UpdateSearch {
         search: Rc<str>,
     },
     SearchResult {
-        result: Result<proto::SearchResponse, gloo_net::Error>,
+        id: u64,
+        response: proto::SearchResponse,
+    },
+    BrowseResult {
+        id: u64,
+        response: proto::BrowseResponse,
+    },
+    // Only shown if it is about the latest listing.
+    ListingFailed {
+        id: u64,
+        failure: Box<Msg>,
     },
     Play {
         filename: Rc<str>,
     },
+    Queue {
+        filename: Rc<str>,
+    },
+    PlaySelected,
+    MoveSelection {
+        by: isize,
+    },
+    ClearSearch,
+    FocusSearch,
+    TogglePause,
+    Seek {
+        seconds: f64,
+        relative: bool,
+    },
+    // Horizontal position of a pointer on the transport bar.
+    SwipeStart {
+        x: i32,
+    },
+    SwipeEnd {
+        x: i32,
+    },
+    // The browser took the pointer, e.g. to scroll.
+    SwipeCancel,
+    Done,
+    Server {
+        message: proto::ServerMessage,
+    },
+    SocketClosed,
+    PollStatus,
+    LoginRequired,
+    Login {
+        password: String,
+    },
+    LoginDone,
+    Failed {
+        error: String,
+    },
+    DismissError,
+}
+
+// The error the server described, or one made up from the status.
+async fn error_response(response: gloo_net::http::Response) -> proto::ErrorResponse {
+    let status = response.status();
+    match response.json::<proto::ErrorResponse>().await {
+        Ok(error) => error,
+        Err(_) => proto::ErrorResponse {
+            code: proto::ErrorCode::Internal,
+            message: format!("HTTP {}", status),
+        },
+    }
+}
+
+fn describe(error: &proto::ErrorResponse) -> String {
+    use proto::ErrorCode::*;
+    let what = match error.code {
+        NotFound => "File not found",
+        AlreadyPlaying => "Something is already playing",
+        NotPlaying => "Nothing is playing",
+        PlayerFailed => "The player failed",
+        DatabaseError => "Database error",
+        Unauthorized => "Not logged in",
+        Forbidden => "Not allowed",
+        BadRequest => "Bad request",
+        Internal => "Server error",
+    };
+    format!("{}: {}", what, error.message)
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    url: &str,
+    abort: &web_sys::AbortSignal,
+) -> Result<T, Msg> {
+    match Request::get(url).abort_signal(Some(abort)).send().await {
+        Ok(response) if response.ok() => response.json::<T>().await.map_err(|error| Msg::Failed {
+            error: format!("Bad response: {}", error),
+        }),
+        resp => Err(failure(resp).await),
+    }
+}
+
+// What to do about an error from the server.
+fn error_msg(error: proto::ErrorResponse) -> Msg {
+    if error.code == proto::ErrorCode::Unauthorized {
+        return Msg::LoginRequired;
+    }
+    Msg::Failed {
+        error: describe(&error),
+    }
+}
+
+// What to do about a request that did not succeed.
+async fn failure(result: Result<gloo_net::http::Response, gloo_net::Error>) -> Msg {
+    match result {
+        Err(error) => Msg::Failed {
+            error: format!("Cannot reach server: {}", error),
+        },
+        Ok(response) => error_msg(error_response(response).await),
+    }
 }
 
 fn build_url(relative: &str) -> Result<web_sys::Url, JsValue> {
@@ -40,7 +205,8 @@ fn build_url(relative: &str) -> Result<web_sys::Url, JsValue> {
 }
 
 fn build_search_url(search: &str) -> String {
-    let url = build_url("/search").expect("programmer error: hardcoded URL is invalid");
+    let url = build_url(&format!("{}/search", proto::API_PREFIX))
+        .expect("programmer error: hardcoded URL is invalid");
     let query = url.search_params();
     query.set("q", search);
     url.set_search(
@@ -54,70 +220,565 @@ fn build_search_url(search: &str) -> String {
         .expect("internal error: bad URL stringification")
 }
 
-fn build_play_url() -> String {
-    let url = build_url("/play").expect("programmer error: hardcoded URL is invalid");
+fn build_browse_url(path: &str) -> String {
+    let url = build_url(&format!("{}/browse", proto::API_PREFIX))
+        .expect("programmer error: hardcoded URL is invalid");
+    let query = url.search_params();
+    query.set("path", path);
+    url.set_search(
+        &query
+            .to_string()
+            .as_string()
+            .expect("internal error: bad URL query stringification"),
+    );
+    url.to_string()
+        .as_string()
+        .expect("internal error: bad URL stringification")
+}
+
+// `endpoint` is relative to `proto::API_PREFIX`, e.g. `play`.
+fn build_api_url(endpoint: &str) -> String {
+    let relative = format!("{}/{}", proto::API_PREFIX, endpoint);
+    let url = build_url(&relative).expect("programmer error: hardcoded URL is invalid");
     url.to_string()
         .as_string()
         .expect("internal error: bad URL stringification")
 }
 
+// The server sets this cookie with the page, and wants it back in a header on every POST.
+fn csrf_token() -> String {
+    let window = web_sys::window().expect("must have JS window");
+    let document = window.document().expect("must have JS document");
+    let cookies = document
+        .unchecked_into::<web_sys::HtmlDocument>()
+        .cookie()
+        .unwrap_or_default();
+    cookies
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == "choosy_csrf")
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_default()
+}
+
+async fn post_json<T: serde::Serialize>(
+    endpoint: &str,
+    body: &T,
+) -> Result<gloo_net::http::Response, gloo_net::Error> {
+    let url = build_api_url(endpoint);
+    let buf = serde_json::to_vec(body).expect("JSON serialize of request must work");
+    let arr = js_sys::Uint8Array::from(&buf[..]);
+    Request::post(&url)
+        .header("content-type", "application/json")
+        .header("x-csrf-token", &csrf_token())
+        .body(arr)
+        .send()
+        .await
+}
+
+// Open the WebSocket, passing what the server says to the component.
+fn connect(link: &yew::html::Scope<Model>) -> Option<mpsc::UnboundedSender<proto::ClientMessage>> {
+    use futures::{SinkExt, StreamExt};
+    use gloo_net::websocket::{futures::WebSocket, Message};
+
+    let url = build_url(&format!("{}/ws", proto::API_PREFIX))
+        .expect("programmer error: hardcoded URL is invalid");
+    url.set_protocol(if url.protocol() == "https:" {
+        "wss:"
+    } else {
+        "ws:"
+    });
+    let url = url
+        .to_string()
+        .as_string()
+        .expect("internal error: bad URL stringification");
+    let socket = match WebSocket::open(&url) {
+        Ok(socket) => socket,
+        Err(error) => {
+            error!(message = "cannot open websocket", %error);
+            return None;
+        }
+    };
+    let (mut write, mut read) = socket.split();
+    let (sender, mut receiver) = mpsc::unbounded::<proto::ClientMessage>();
+    wasm_bindgen_futures::spawn_local(async move {
+        while let Some(message) = receiver.next().await {
+            let text =
+                serde_json::to_string(&message).expect("JSON serialize of request must work");
+            if write.send(Message::Text(text)).await.is_err() {
+                // The reader sees the socket close, too.
+                break;
+            }
+        }
+    });
+    let link = link.clone();
+    wasm_bindgen_futures::spawn_local(async move {
+        while let Some(Ok(message)) = read.next().await {
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Bytes(_) => continue,
+            };
+            match serde_json::from_str::<proto::ServerMessage>(&text) {
+                Ok(message) => link.send_message(Msg::Server { message }),
+                Err(error) => error!(message = "bad websocket message", %error),
+            }
+        }
+        link.send_message(Msg::SocketClosed);
+    });
+    Some(sender)
+}
+
+// Enter and Space press a focused button or follow a focused link, and must not also do something else.
+fn is_control(target: &web_sys::EventTarget) -> bool {
+    target.has_type::<web_sys::HtmlButtonElement>()
+        || target.has_type::<web_sys::HtmlAnchorElement>()
+}
+
+// What a key does, like in fzf; single letters only when not typing in the search box.
+fn key_action(event: &KeyboardEvent) -> Option<Msg> {
+    if event.alt_key() || event.ctrl_key() || event.meta_key() {
+        return None;
+    }
+    let target = event.target();
+    let typing = target.as_ref().map_or(false, |target| {
+        target.has_type::<web_sys::HtmlInputElement>()
+    });
+    let on_control = target.as_ref().map_or(false, is_control);
+    let msg = match event.key().as_str() {
+        "ArrowDown" => Msg::MoveSelection { by: 1 },
+        "ArrowUp" => Msg::MoveSelection { by: -1 },
+        "PageDown" => Msg::MoveSelection { by: 10 },
+        "PageUp" => Msg::MoveSelection { by: -10 },
+        "Enter" if !on_control => Msg::PlaySelected,
+        "Escape" => Msg::ClearSearch,
+        "j" if !typing => Msg::MoveSelection { by: 1 },
+        "k" if !typing => Msg::MoveSelection { by: -1 },
+        "/" if !typing => Msg::FocusSearch,
+        " " if !typing && !on_control => Msg::TogglePause,
+        _ => return None,
+    };
+    Some(msg)
+}
+
+fn listen_keys(link: &yew::html::Scope<Model>) -> gloo_events::EventListener {
+    let link = link.clone();
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .expect("must have JS document");
+    let options = gloo_events::EventListenerOptions::enable_prevent_default();
+    gloo_events::EventListener::new_with_options(&document, "keydown", options, move |event| {
+        let event = event.unchecked_ref::<KeyboardEvent>();
+        if let Some(msg) = key_action(event) {
+            event.prevent_default();
+            link.send_message(msg);
+        }
+    })
+}
+
+fn format_time(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => {
+            let seconds = seconds as u64;
+            format!("{}:{:02}", seconds / 60, seconds % 60)
+        }
+        None => "-".to_string(),
+    }
+}
+
+fn location() -> web_sys::Location {
+    let window = web_sys::window().expect("must have JS window");
+    window.location()
+}
+
+// Links from elsewhere can use paths instead of the fragment; move those after the `#`.
+fn initial_route() -> Route {
+    let location = location();
+    let hash = location.hash().unwrap_or_default();
+    let path = location.pathname().unwrap_or_default();
+    if !hash.is_empty() || path == "/" {
+        return Route::parse(&hash);
+    }
+    let search = location.search().unwrap_or_default();
+    let route = Route::parse(&format!("{}{}", path, search));
+    set_route(&route, false);
+    route
+}
+
+// Adding to the history makes the browser tell about it with `hashchange`; replacing does not.
+fn set_route(route: &Route, push: bool) {
+    let url = format!("/{}", route.to_fragment());
+    if push {
+        if let Err(error) = location().set_hash(&route.to_fragment()) {
+            error!(message = "cannot set location", ?error);
+        }
+        return;
+    }
+    let history = web_sys::window()
+        .expect("must have JS window")
+        .history()
+        .expect("must have JS history");
+    if let Err(error) = history.replace_state_with_url(&JsValue::NULL, "", Some(&url)) {
+        error!(message = "cannot set location", ?error);
+    }
+}
+
+// The last path component, for listing a directory.
+fn base_name(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(slash) => &path[slash + 1..],
+        None => path,
+    }
+}
+
 impl Component for Model {
     type Message = Msg;
     type Properties = ();
 
     fn create(ctx: &Context<Self>) -> Self {
-        ctx.link().send_message(Msg::UpdateSearch {
-            search: Rc::from(""),
-        });
-        Self {
-            search: Rc::from(""),
+        ctx.link().send_message(Msg::Load);
+        let route = initial_route();
+        let search = match &route {
+            Route::Search { q } => Rc::from(q.as_str()),
+            _ => Rc::from(""),
+        };
+        let mut model = Self {
+            route,
+            _route_change: {
+                let link = ctx.link().clone();
+                let window = web_sys::window().expect("must have JS window");
+                gloo_events::EventListener::new(&window, "hashchange", move |_| {
+                    let hash = location().hash().unwrap_or_default();
+                    link.send_message(Msg::RouteChanged {
+                        route: Route::parse(&hash),
+                    });
+                })
+            },
+            search,
+            directories: Vec::new(),
             files: BTreeMap::new(),
-        }
+            need_login: false,
+            password_input: NodeRef::default(),
+            search_input: NodeRef::default(),
+            selected: 0,
+            scroll_to_selected: false,
+            keys: Some(listen_keys(ctx.link())),
+            error: None,
+            socket: connect(ctx.link()),
+            status_poll: None,
+            next_id: 0,
+            latest_listing: 0,
+            commands: BTreeSet::new(),
+            loading: false,
+            in_flight: None,
+            search_delay: None,
+            now_playing: None,
+            transport: NodeRef::default(),
+            swipe_start: None,
+        };
+        model.poll_status_without_socket(ctx);
+        model
     }
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
+            Msg::Load => {
+                self.load(ctx);
+                return false;
+            }
+            Msg::Navigate { route } => {
+                // Comes back as `RouteChanged`.
+                set_route(&route, true);
+                return false;
+            }
+            Msg::RouteChanged { route } => {
+                if route == self.route {
+                    return false;
+                }
+                if let Route::Search { q } = &route {
+                    self.search = Rc::from(q.as_str());
+                }
+                self.route = route;
+                self.load(ctx);
+            }
             Msg::UpdateSearch { search } => {
                 self.search = search.clone();
-                ctx.link().send_future(async move {
-                    let result = {
-                        let url = build_search_url(&search);
-                        let resp = Request::get(&url).send().await;
-                        match resp {
-                            Ok(response) => response.json::<proto::SearchResponse>().await,
-                            Err(error) => Err(error),
-                        }
-                    };
-                    Msg::SearchResult { result }
-                });
+                let route = Route::Search {
+                    q: search.to_string(),
+                };
+                // Typing does not fill the history, but leaving another view does.
+                let push = !matches!(self.route, Route::Search { .. });
+                set_route(&route, push);
+                self.route = route;
+                let link = ctx.link().clone();
+                // Replacing the timeout cancels the one before.
+                self.search_delay = Some(gloo_timers::callback::Timeout::new(
+                    SEARCH_DELAY_MILLIS,
+                    move || link.send_message(Msg::Load),
+                ));
             }
 
-            Msg::SearchResult { result } => {
-                match result {
-                    Err(error) => {
-                        error!(message = "search failed", ?error);
-                    }
-                    Ok(response) => {
-                        self.files.clear();
-                        self.files.extend(response.items.iter().map(|item| {
-                            // Do not ask me why this has to be here.
-                            // All I know is it didn't work, and I copied this from `Rc::from` for `From<String>`.
-                            let s = &item.filename[..];
-                            (Rc::from(s), ())
-                        }));
-                    }
+            Msg::SearchResult { id, response } => {
+                if id != self.latest_listing {
+                    return false;
+                }
+                self.loading = false;
+                self.in_flight = None;
+                self.error = None;
+                self.selected = 0;
+                self.directories.clear();
+                self.files.clear();
+                self.files.extend(response.items.iter().map(|item| {
+                    // Do not ask me why this has to be here.
+                    // All I know is it didn't work, and I copied this from `Rc::from` for `From<String>`.
+                    let s = &item.filename[..];
+                    (Rc::from(s), ())
+                }));
+            }
+            Msg::BrowseResult { id, response } => {
+                if id != self.latest_listing {
+                    return false;
                 }
+                self.loading = false;
+                self.in_flight = None;
+                self.error = None;
+                self.selected = 0;
+                self.directories = response
+                    .directories
+                    .iter()
+                    .map(|directory| Rc::from(directory.as_str()))
+                    .collect();
+                self.files = response
+                    .files
+                    .iter()
+                    .map(|file| (Rc::from(file.filename.as_str()), ()))
+                    .collect();
+            }
+            Msg::ListingFailed { id, failure } => {
+                // Includes the requests that were cancelled.
+                if id != self.latest_listing {
+                    return false;
+                }
+                self.loading = false;
+                self.in_flight = None;
+                return self.update(ctx, *failure);
+            }
+            Msg::Queue { filename } => {
+                self.command(
+                    ctx,
+                    proto::Request::Queue(proto::QueueCommand {
+                        filename: filename.to_string(),
+                    }),
+                );
+                return false;
             }
             Msg::Play { filename } => {
-                // TODO indicate things in UI somehow, SSE for play status?
                 info!(message = "playing", filename = filename.as_ref());
+                self.command(
+                    ctx,
+                    proto::Request::Play(proto::PlayCommand {
+                        filename: filename.to_string(),
+                    }),
+                );
+                return false;
+            }
+            Msg::PlaySelected => {
+                if let Route::File { path } = &self.route {
+                    ctx.link().send_message(Msg::Play {
+                        filename: Rc::from(path.as_str()),
+                    });
+                } else if let Some(directory) = self.directories.get(self.selected) {
+                    ctx.link().send_message(Msg::Navigate {
+                        route: Route::Browse {
+                            path: directory.to_string(),
+                        },
+                    });
+                } else if let Some(filename) = self
+                    .files
+                    .keys()
+                    .nth(self.selected - self.directories.len())
+                {
+                    ctx.link().send_message(Msg::Play {
+                        filename: filename.clone(),
+                    });
+                }
+                return false;
+            }
+            Msg::MoveSelection { by } => {
+                let count = self.directories.len() + self.files.len();
+                let last = count.saturating_sub(1) as isize;
+                self.selected = (self.selected as isize + by).clamp(0, last) as usize;
+                self.scroll_to_selected = true;
+            }
+            Msg::ClearSearch => {
+                ctx.link().send_message(Msg::UpdateSearch {
+                    search: Rc::from(""),
+                });
+                return false;
+            }
+            Msg::FocusSearch => {
+                if let Some(input) = self.search_input.cast::<web_sys::HtmlInputElement>() {
+                    let _ = input.focus();
+                }
+                return false;
+            }
+            Msg::TogglePause => {
+                if self.now_playing.is_some() {
+                    let command = proto::PauseCommand { pause: None };
+                    self.command(ctx, proto::Request::Pause(command));
+                }
+                return false;
+            }
+            Msg::Seek { seconds, relative } => {
+                let command = proto::SeekCommand { seconds, relative };
+                self.command(ctx, proto::Request::Seek(command));
+                return false;
+            }
+            Msg::SwipeStart { x } => {
+                self.swipe_start = Some(x);
+                return false;
+            }
+            Msg::SwipeCancel => {
+                self.swipe_start = None;
+                return false;
+            }
+            Msg::SwipeEnd { x } => {
+                let start = match self.swipe_start.take() {
+                    Some(start) => start,
+                    None => return false,
+                };
+                let width = self
+                    .transport
+                    .cast::<web_sys::Element>()
+                    .map(|bar| bar.client_width())
+                    .unwrap_or(0);
+                let distance = x - start;
+                if self.now_playing.is_some() && width > 0 && distance.abs() >= SWIPE_MIN_PIXELS {
+                    ctx.link().send_message(Msg::Seek {
+                        seconds: SWIPE_SECONDS * f64::from(distance) / f64::from(width),
+                        relative: true,
+                    });
+                }
+                return false;
+            }
+            Msg::Done => {
+                self.error = None;
+            }
+            Msg::Server {
+                message: proto::ServerMessage::Status(status),
+            } => {
+                self.now_playing = status.playing;
+            }
+            Msg::Server {
+                message: proto::ServerMessage::Response { id, response },
+            } => match response {
+                proto::Response::Error(error) if id == self.latest_listing => {
+                    ctx.link().send_message(Msg::ListingFailed {
+                        id,
+                        failure: Box::new(error_msg(error)),
+                    });
+                    return false;
+                }
+                proto::Response::Search(response) => {
+                    ctx.link().send_message(Msg::SearchResult { id, response });
+                    return false;
+                }
+                proto::Response::Browse(response) => {
+                    ctx.link().send_message(Msg::BrowseResult { id, response });
+                    return false;
+                }
+                proto::Response::Status(status) => {
+                    self.now_playing = status.playing;
+                }
+                proto::Response::Done => {
+                    self.commands.remove(&id);
+                    ctx.link().send_message(Msg::Done);
+                    return false;
+                }
+                proto::Response::Error(error) => {
+                    if self.commands.remove(&id) {
+                        ctx.link().send_message(error_msg(error));
+                    }
+                    return false;
+                }
+            },
+            Msg::SocketClosed => {
+                // TODO reconnect, with backoff
+                info!("websocket closed, using HTTP");
+                self.socket = None;
+                self.commands.clear();
+                self.now_playing = None;
+                self.poll_status_without_socket(ctx);
+                // Whatever was asked over the socket will never be answered.
+                self.load(ctx);
+            }
+            Msg::PollStatus => {
+                ctx.link().send_future(async {
+                    // Never cancelled; the next poll is a while away.
+                    let controller =
+                        web_sys::AbortController::new().expect("must have JS AbortController");
+                    let url = build_api_url("status");
+                    let status = match get_json(&url, &controller.signal()).await {
+                        Ok(status) => status,
+                        Err(failure @ Msg::LoginRequired) => return failure,
+                        // Not worth an error every second; the next poll tries again.
+                        Err(_) => proto::StatusResponse { playing: None },
+                    };
+                    Msg::Server {
+                        message: proto::ServerMessage::Status(status),
+                    }
+                });
+                return false;
+            }
+            Msg::LoginRequired => {
+                self.need_login = true;
+                // Keys belong to the password box now.
+                self.keys = None;
+                // Polling only gets refused until then.
+                self.status_poll = None;
+            }
+            Msg::Login { password } => {
+                ctx.link().send_future(async move {
+                    let request = proto::LoginRequest { password };
+                    match post_json("login", &request).await {
+                        Ok(response) if response.ok() => Msg::LoginDone,
+                        // Not `failure`, which would only ask for the password again.
+                        Ok(response) => Msg::Failed {
+                            error: describe(&error_response(response).await),
+                        },
+                        Err(error) => Msg::Failed {
+                            error: format!("Cannot reach server: {}", error),
+                        },
+                    }
+                });
+                return false;
+            }
+            Msg::LoginDone => {
+                self.need_login = false;
+                self.keys = Some(listen_keys(ctx.link()));
+                self.error = None;
+                // Refused before logging in.
+                if self.socket.is_none() {
+                    self.socket = connect(ctx.link());
+                }
+                self.poll_status_without_socket(ctx);
+                self.load(ctx);
+            }
+            Msg::Failed { error } => {
+                error!(message = "request failed", %error);
+                self.error = Some(error);
+            }
+            Msg::DismissError => {
+                self.error = None;
             }
         };
         true
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
-        let entries = self.files.iter();
+        if self.need_login {
+            return self.view_login(ctx);
+        }
         let oninput = ctx.link().callback_future(|event: InputEvent| async move {
             let target = event.target().expect("oninput event must have target");
             let search: String = target.unchecked_into::<web_sys::HtmlInputElement>().value();
@@ -125,52 +786,358 @@ impl Component for Model {
                 search: Rc::from(search),
             }
         });
+        let main = match &self.route {
+            Route::File { path } => self.view_file(ctx, path),
+            Route::Search { .. } | Route::Browse { .. } => self.view_list(ctx),
+        };
         html! {
             <>
-                <div style="position: sticky; top: 0;">
+                <div class="top">
+                    {self.view_error(ctx)}
                     <input
+                        type="search"
                         placeholder="Search"
+                        ref={self.search_input.clone()}
                         value={yew::virtual_dom::AttrValue::from(self.search.clone())}
                         oninput={oninput}
-                        // border-box makes borders be within width, not outside it
-                        style="width: 100%;"
                     />
+                    {if self.loading {
+                        html! { <div class="loading" title="Loading" /> }
+                    } else {
+                        html! {}
+                    }}
+                    {self.view_crumbs()}
                 </div>
-                <ul style="padding-right: 10px;">
-                  {for entries.map(|(filename, _)| {
-                    let tmp = filename.clone();
-                    let callback = ctx.link().callback_future(move |_| {
-                        let tmp = tmp.clone();
-                        async move {
-                            let url = build_play_url();
-                            let cmd = proto::PlayCommand{
-                                filename: tmp.to_string()
-                            };
-                            let buf = serde_json::to_vec(&cmd).expect("JSON serialize of PlayCommand must work");
-                            let arr = js_sys::Uint8Array::from(&buf[..]);
-                            let resp = Request::post(&url)
-                                .header("content-type", "application/json")
-                                .body(arr)
-                                .send().await;
-                            match resp {
-                                Ok(_response) => {
-                                    // TODO status of playback as SSE?
-                                },
-                                Err(error) => {
-                                    error!(message="requesting play failed", ?error);
-                                },
-                            }
-                            Msg::Play { filename: tmp }
-                        }
-                    });
-                    html! {
-                        <li onclick={callback}>{filename}</li>
-                    }
-                    })}
-                </ul>
+                {main}
+                {self.view_transport(ctx)}
             </>
         }
     }
+
+    fn rendered(&mut self, _ctx: &Context<Self>, _first_render: bool) {
+        if !std::mem::take(&mut self.scroll_to_selected) {
+            return;
+        }
+        let selected = web_sys::window()
+            .and_then(|window| window.document())
+            .and_then(|document| document.get_element_by_id("selected"));
+        if let Some(selected) = selected {
+            let mut options = web_sys::ScrollIntoViewOptions::new();
+            options.block(web_sys::ScrollLogicalPosition::Nearest);
+            selected.scroll_into_view_with_scroll_into_view_options(&options);
+        }
+    }
+}
+
+impl Model {
+    fn load(&mut self, ctx: &Context<Self>) {
+        self.search_delay = None;
+        if let Some(in_flight) = self.in_flight.take() {
+            in_flight.abort();
+        }
+        let id = self.next_id();
+        self.latest_listing = id;
+        let request = match &self.route {
+            Route::Search { q } => proto::Request::Search { q: q.clone() },
+            Route::Browse { path } => proto::Request::Browse { path: path.clone() },
+            // Everything there is to show is in the route.
+            Route::File { .. } => {
+                self.loading = false;
+                return;
+            }
+        };
+        self.loading = true;
+        let request = match self.send(id, request) {
+            Ok(()) => return,
+            Err(request) => request,
+        };
+        let controller = web_sys::AbortController::new().expect("must have JS AbortController");
+        let abort = controller.signal();
+        self.in_flight = Some(controller);
+        ctx.link().send_future(async move {
+            let result = match request {
+                proto::Request::Search { q } => get_json(&build_search_url(&q), &abort)
+                    .await
+                    .map(|response| Msg::SearchResult { id, response }),
+                proto::Request::Browse { path } => get_json(&build_browse_url(&path), &abort)
+                    .await
+                    .map(|response| Msg::BrowseResult { id, response }),
+                _ => unreachable!("not a listing: {:?}", request),
+            };
+            match result {
+                Ok(msg) => msg,
+                Err(failure) => Msg::ListingFailed {
+                    id,
+                    failure: Box::new(failure),
+                },
+            }
+        });
+    }
+
+    fn poll_status_without_socket(&mut self, ctx: &Context<Self>) {
+        if self.socket.is_some() {
+            self.status_poll = None;
+            return;
+        }
+        if self.status_poll.is_some() {
+            return;
+        }
+        let link = ctx.link().clone();
+        self.status_poll = Some(gloo_timers::callback::Interval::new(
+            STATUS_POLL_MILLIS,
+            move || link.send_message(Msg::PollStatus),
+        ));
+        ctx.link().send_message(Msg::PollStatus);
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Send over the WebSocket, if it is open; otherwise the request is given back, for the caller to use HTTP.
+    fn send(&self, id: u64, request: proto::Request) -> Result<(), proto::Request> {
+        match &self.socket {
+            Some(socket) => socket
+                .unbounded_send(proto::ClientMessage { id, request })
+                .map_err(|error| error.into_inner().request),
+            None => Err(request),
+        }
+    }
+
+    // Send a command that only succeeds or fails, by whichever way works.
+    fn command(&mut self, ctx: &Context<Self>, request: proto::Request) {
+        let id = self.next_id();
+        let request = match self.send(id, request) {
+            Ok(()) => {
+                self.commands.insert(id);
+                return;
+            }
+            Err(request) => request,
+        };
+        ctx.link().send_future(async move {
+            let result = match &request {
+                proto::Request::Play(command) => post_json("play", command).await,
+                proto::Request::Queue(command) => post_json("queue", command).await,
+                proto::Request::Pause(command) => post_json("pause", command).await,
+                proto::Request::Seek(command) => post_json("seek", command).await,
+                proto::Request::Search { .. }
+                | proto::Request::Browse { .. }
+                | proto::Request::Status => {
+                    unreachable!("not a command: {:?}", request)
+                }
+            };
+            match result {
+                Ok(response) if response.ok() => Msg::Done,
+                resp => failure(resp).await,
+            }
+        });
+    }
+
+    // What is playing, with big buttons for a phone; swipe sideways to seek.
+    fn view_transport(&self, ctx: &Context<Self>) -> Html {
+        let onpointerdown = ctx.link().callback(|event: PointerEvent| Msg::SwipeStart {
+            x: event.client_x(),
+        });
+        let onpointerup = ctx.link().callback(|event: PointerEvent| Msg::SwipeEnd {
+            x: event.client_x(),
+        });
+        let onpointercancel = ctx.link().callback(|_: PointerEvent| Msg::SwipeCancel);
+        let seek = |seconds: f64| {
+            ctx.link().callback(move |_: MouseEvent| Msg::Seek {
+                seconds,
+                relative: true,
+            })
+        };
+        let toggle = ctx.link().callback(|_: MouseEvent| Msg::TogglePause);
+        let (title, paused, progress) = match &self.now_playing {
+            None => ("Nothing playing".to_string(), true, html! {}),
+            Some(playing) => {
+                let title = format!(
+                    "{} {} / {}",
+                    playing.filename,
+                    format_time(playing.position),
+                    format_time(playing.duration)
+                );
+                let progress = match (playing.position, playing.duration) {
+                    (Some(position), Some(duration)) => html! {
+                        <progress max={duration.to_string()} value={position.to_string()} />
+                    },
+                    _ => html! {},
+                };
+                (title, playing.paused, progress)
+            }
+        };
+        let idle = self.now_playing.is_none();
+        html! {
+            <div class="transport" ref={self.transport.clone()} {onpointerdown} {onpointerup} {onpointercancel}>
+                <div class="title">{title}</div>
+                {progress}
+                <div class="buttons">
+                    <button disabled={idle} onclick={seek(-10.0)} title="Back 10 seconds">{"⏪"}</button>
+                    <button disabled={idle} onclick={toggle} title="Pause or resume">
+                        {if paused { "▶" } else { "⏸" }}
+                    </button>
+                    <button disabled={idle} onclick={seek(30.0)} title="Forward 30 seconds">{"⏩"}</button>
+                </div>
+            </div>
+        }
+    }
+
+    // Links up the directory tree from what is shown.
+    fn view_crumbs(&self) -> Html {
+        let path = match &self.route {
+            Route::Search { .. } => {
+                let all = Route::Browse {
+                    path: String::new(),
+                };
+                return html! {
+                    <nav class="crumbs"><a href={all.to_fragment()}>{"Browse all files"}</a></nav>
+                };
+            }
+            Route::Browse { path } | Route::File { path } => path,
+        };
+        let mut crumbs = vec![(String::new(), "All files")];
+        for (slash, _) in path.match_indices('/') {
+            let directory = &path[..=slash];
+            crumbs.push((directory.to_string(), base_name(directory)));
+        }
+        html! {
+            <nav class="crumbs">
+                {for crumbs.into_iter().map(|(path, name)| {
+                    let route = Route::Browse { path };
+                    html! { <a href={route.to_fragment()}>{name}</a> }
+                })}
+            </nav>
+        }
+    }
+
+    fn view_entry(
+        &self,
+        index: usize,
+        class: &'static str,
+        onclick: Callback<MouseEvent>,
+        content: Html,
+    ) -> Html {
+        if index == self.selected {
+            html! {
+                <li id="selected" class={classes!(class, "selected")} {onclick}>{content}</li>
+            }
+        } else {
+            html! {
+                <li class={class} {onclick}>{content}</li>
+            }
+        }
+    }
+
+    fn view_list(&self, ctx: &Context<Self>) -> Html {
+        let browsing = matches!(self.route, Route::Browse { .. });
+        let directories = self
+            .directories
+            .iter()
+            .enumerate()
+            .map(|(index, directory)| {
+                let route = Route::Browse {
+                    path: directory.to_string(),
+                };
+                let onclick = ctx.link().callback(move |_: MouseEvent| Msg::Navigate {
+                    route: route.clone(),
+                });
+                let content = html! { {format!("📁 {}", base_name(directory))} };
+                self.view_entry(index, "directory", onclick, content)
+            });
+        let files = self.files.keys().enumerate().map(|(index, filename)| {
+            let tmp = filename.clone();
+            let onclick = ctx.link().callback(move |_: MouseEvent| Msg::Play {
+                filename: tmp.clone(),
+            });
+            let details = Route::File {
+                path: filename.to_string(),
+            };
+            let name: &str = if browsing {
+                base_name(filename)
+            } else {
+                filename
+            };
+            let content = html! {
+                <>
+                    <a
+                        class="details"
+                        href={details.to_fragment()}
+                        title="Details"
+                        // Not a click on the entry, which would play it.
+                        onclick={Callback::from(|event: MouseEvent| event.stop_propagation())}
+                    >{"ⓘ"}</a>
+                    {name}
+                </>
+            };
+            self.view_entry(self.directories.len() + index, "file", onclick, content)
+        });
+        html! {
+            <ul class="files">
+                {for directories}
+                {for files}
+            </ul>
+        }
+    }
+
+    fn view_file(&self, ctx: &Context<Self>, path: &str) -> Html {
+        let filename: Rc<str> = Rc::from(path);
+        let play = {
+            let filename = filename.clone();
+            ctx.link().callback(move |_: MouseEvent| Msg::Play {
+                filename: filename.clone(),
+            })
+        };
+        let queue = ctx.link().callback(move |_: MouseEvent| Msg::Queue {
+            filename: filename.clone(),
+        });
+        html! {
+            <div class="file">
+                <h2>{base_name(path)}</h2>
+                <p>{path}</p>
+                <div class="buttons">
+                    <button onclick={play}>{"Play"}</button>
+                    <button onclick={queue}>{"Play next"}</button>
+                </div>
+            </div>
+        }
+    }
+
+    fn view_error(&self, ctx: &Context<Self>) -> Html {
+        match &self.error {
+            None => html! {},
+            Some(error) => html! {
+                <div
+                    class="error"
+                    title="Click to dismiss"
+                    onclick={ctx.link().callback(|_: MouseEvent| Msg::DismissError)}
+                >{error}</div>
+            },
+        }
+    }
+
+    fn view_login(&self, ctx: &Context<Self>) -> Html {
+        let password_input = self.password_input.clone();
+        // A form, so Enter in the password box logs in; the key listener is off until then.
+        let onsubmit = ctx.link().callback(move |event: FocusEvent| {
+            event.prevent_default();
+            let password = password_input
+                .cast::<web_sys::HtmlInputElement>()
+                .map(|input| input.value())
+                .unwrap_or_default();
+            Msg::Login { password }
+        });
+        html! {
+            <div style="padding: 10px;">
+                {self.view_error(ctx)}
+                <form {onsubmit}>
+                    <input type="password" placeholder="Password" ref={self.password_input.clone()} />
+                    <button type="submit">{"Log in"}</button>
+                </form>
+            </div>
+        }
+    }
 }
 
 #[wasm_bindgen(start)]
@@ -0,0 +1,148 @@
+//! Where in the UI the user is, kept after the `#` in the URL so it can be bookmarked, and back and forward work.
+//!
+//! - `#/search?q=star`
+//! - `#/browse/Movies/`
+//! - `#/file/Movies/a.mkv`
+//!
+//! The server also answers the same paths without the `#`, for links from elsewhere.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Search { q: String },
+    Browse { path: String },
+    File { path: String },
+}
+
+impl Route {
+    /// Parse `#/...`, or the same without the `#`; anything else is an empty search.
+    pub fn parse(fragment: &str) -> Route {
+        let fragment = fragment.trim_start_matches('#');
+        if fragment == "/browse" {
+            return Route::Browse {
+                path: String::new(),
+            };
+        }
+        if let Some(path) = fragment.strip_prefix("/browse/") {
+            return Route::Browse {
+                path: decode(path, false),
+            };
+        }
+        if let Some(path) = fragment.strip_prefix("/file/") {
+            if !path.is_empty() {
+                return Route::File {
+                    path: decode(path, false),
+                };
+            }
+        }
+        let q = fragment
+            .strip_prefix("/search?")
+            .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("q=")))
+            .map(|q| decode(q, true))
+            .unwrap_or_default();
+        Route::Search { q }
+    }
+
+    pub fn to_fragment(&self) -> String {
+        match self {
+            Route::Search { q } => format!("#/search?q={}", encode(q, false)),
+            Route::Browse { path } => format!("#/browse/{}", encode(path, true)),
+            Route::File { path } => format!("#/file/{}", encode(path, true)),
+        }
+    }
+}
+
+fn encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+// Invalid escapes are kept as they are.
+fn decode(s: &str, plus_is_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte == b'%' {
+            let hex = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(decoded) = hex {
+                out.push(decoded);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(if plus_is_space && byte == b'+' {
+            b' '
+        } else {
+            byte
+        });
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for route in [
+            Route::Search { q: String::new() },
+            Route::Search {
+                q: "star wars & 100%".to_string(),
+            },
+            Route::Browse {
+                path: String::new(),
+            },
+            Route::Browse {
+                path: "Movies/Old ones/".to_string(),
+            },
+            Route::File {
+                path: "Movies/Ämelie #2?.mkv".to_string(),
+            },
+        ] {
+            assert_eq!(Route::parse(&route.to_fragment()), route);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let search = |q: &str| Route::Search { q: q.to_string() };
+        assert_eq!(Route::parse(""), search(""));
+        assert_eq!(Route::parse("#/"), search(""));
+        assert_eq!(Route::parse("#/nonsense"), search(""));
+        assert_eq!(Route::parse("#/search?x=1&q=a+b%2Fc"), search("a b/c"));
+        assert_eq!(Route::parse("/search?q=50%"), search("50%"));
+        assert_eq!(
+            Route::parse("/browse/Movies/"),
+            Route::Browse {
+                path: "Movies/".to_string()
+            }
+        );
+        assert_eq!(
+            Route::parse("#/browse"),
+            Route::Browse {
+                path: String::new()
+            }
+        );
+        assert_eq!(Route::parse("#/file/"), search(""));
+        assert_eq!(
+            Route::parse("#/file/a%20b.mkv"),
+            Route::File {
+                path: "a b.mkv".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,48 @@
+// Makes choosy installable as an app, and lets it open while the server is unreachable.
+// The page itself goes to the network first, so a new version of choosy is picked up on the next load.
+// The API is never cached.
+
+const CACHE = "choosy-v1";
+const SHELL = [
+  "/",
+  "/choosy_frontend.js",
+  "/choosy_frontend_bg.wasm",
+  "/manifest.webmanifest",
+  "/icon.svg",
+];
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(caches.open(CACHE).then((cache) => cache.addAll(SHELL)));
+});
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    caches
+      .keys()
+      .then((keys) =>
+        Promise.all(keys.filter((key) => key !== CACHE).map((key) => caches.delete(key)))
+      )
+  );
+});
+
+self.addEventListener("fetch", (event) => {
+  const url = new URL(event.request.url);
+  if (
+    event.request.method !== "GET" ||
+    url.origin !== self.location.origin ||
+    !SHELL.includes(url.pathname)
+  ) {
+    return;
+  }
+  event.respondWith(
+    fetch(event.request)
+      .then((response) => {
+        if (response.ok) {
+          const copy = response.clone();
+          caches.open(CACHE).then((cache) => cache.put(event.request, copy));
+        }
+        return response;
+      })
+      .catch(() => caches.match(event.request))
+  );
+});
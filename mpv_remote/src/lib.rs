@@ -3,8 +3,11 @@ use futures_channel::oneshot;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, log, trace, warn};
 // WAITING incorrect error from rust-analyzer https://github.com/rust-analyzer/rust-analyzer/issues/6038
-use std::os::unix::io::{AsRawFd, FromRawFd};
-use std::{ffi::OsStr, sync::Arc};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
@@ -18,11 +21,53 @@ pub struct Config {
     // External bug: MPV never starts fullscreen under ChromeOS Linux container, even if pressing "f" later works.
     #[builder(default = "true")]
     fullscreen: bool,
+    /// Extra mpv options, passed on the command line as `--name=value`.
+    /// Flags take `yes` or `no` as their value.
+    #[builder(default)]
+    options: BTreeMap<String, String>,
+    /// The mpv executable, looked up in `PATH` unless it contains a slash.
+    #[builder(setter(into), default = "OsString::from(\"mpv\")")]
+    executable: OsString,
+    /// Extra environment variables for mpv, e.g. `DISPLAY` or `PULSE_SERVER`.
+    #[builder(default)]
+    env: BTreeMap<OsString, OsString>,
+    /// Working directory for mpv, instead of inheriting ours.
+    #[builder(setter(into, strip_option), default)]
+    current_dir: Option<PathBuf>,
+    /// Command and arguments to run mpv under, e.g. `["systemd-run", "--user", "--scope"]`.
+    #[builder(default)]
+    wrapper: Vec<OsString>,
+    /// Also listen for IPC clients on this Unix socket, so others can attach with `MPV::connect`.
+    #[builder(setter(into, strip_option), default)]
+    ipc_server: Option<PathBuf>,
 }
 
-// MPV runs the mpv video player in a subprocess and observes the playback progress.
+impl ConfigBuilder {
+    /// Set a single extra mpv option, replacing any earlier value for it.
+    pub fn option<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) -> &mut Self {
+        self.options
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.into(), value.into());
+        self
+    }
+
+    /// Set a single extra environment variable for mpv.
+    pub fn env_var<N: Into<OsString>, V: Into<OsString>>(
+        &mut self,
+        name: N,
+        value: V,
+    ) -> &mut Self {
+        self.env
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.into(), value.into());
+        self
+    }
+}
+
+// MPV runs the mpv video player in a subprocess, or attaches to one running elsewhere, and observes the playback progress.
 pub struct MPV {
-    child: tokio::process::Child,
+    // None if we attached to an mpv we didn't start.
+    child: Option<tokio::process::Child>,
     ipc: Arc<IPCState>,
     ipc_task: tokio::task::JoinHandle<Result<(), std::io::Error>>,
 }
@@ -44,34 +89,102 @@ pub enum StartError {
 
 impl Config {
     pub fn play(&self, path: &OsStr) -> Result<MPV, StartError> {
+        self.spawn(Some(path))
+    }
+
+    /// Start mpv with no file, keeping a window open, for use with `MPV::loadfile`.
+    pub fn start_idle(&self) -> Result<MPV, StartError> {
+        self.spawn(None)
+    }
+
+    fn spawn(&self, path: Option<&OsStr>) -> Result<MPV, StartError> {
         let (socket, child_socket) = match tokio::net::UnixStream::pair() {
             Ok(pair) => pair,
             Err(error) => return Err(StartError::SocketCreate(error)),
         };
 
         // RUST-WART I find it hard to believe that Rust does not offer any mechanism to pass a UnixStream to a child process as an fd, without resorting to unsafe.
-        let child_file = unsafe {
-            let child_fd = child_socket.as_raw_fd();
-            std::fs::File::from_raw_fd(child_fd)
+        //
+        // Convert to a plain fd so the `File` is its only owner; it's closed when `cmd` is dropped, after the fork.
+        // The child gets a blocking socket, like any other inherited stdin.
+        let child_fd = match child_socket
+            .into_std()
+            .and_then(|std_socket| std_socket.set_nonblocking(false).map(|()| std_socket))
+        {
+            Ok(std_socket) => std_socket.into_raw_fd(),
+            Err(error) => return Err(StartError::SocketCreate(error)),
+        };
+        let child_file = unsafe { std::fs::File::from_raw_fd(child_fd) };
+        let mut cmd = self.command(path);
+        cmd.stdin(child_file);
+        let child = match cmd.spawn() {
+            Ok(proc) => proc,
+            Err(error) => return Err(StartError::StartingMPV(error)),
         };
-        let mut cmd = tokio::process::Command::new("mpv");
+
+        Ok(MPV::from_socket(socket, Some(child)))
+    }
+
+    // The mpv command line, talking IPC on its stdin.
+    fn command(&self, path: Option<&OsStr>) -> tokio::process::Command {
+        let mut cmd = match self.wrapper.split_first() {
+            None => tokio::process::Command::new(&self.executable),
+            Some((wrapper, wrapper_args)) => {
+                let mut cmd = tokio::process::Command::new(wrapper);
+                cmd.args(wrapper_args).arg(&self.executable);
+                cmd
+            }
+        };
+        cmd.envs(&self.env);
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
         cmd
             // RUST-WART I seem to be unable to pass FDs other than 0/1/2, without managing the fork+exec myself?
             .arg("--input-ipc-client=fd://0")
-            .arg("--no-input-terminal")
-            .stdin(child_file);
+            .arg("--no-input-terminal");
         if self.fullscreen {
             cmd.arg("--fullscreen");
         }
-        // TODO make non-absolute paths to start with "./" so mpv won't parse them as URLs
-        cmd.arg("--").arg(path);
-        let child = match cmd.spawn() {
-            Ok(proc) => proc,
-            Err(error) => return Err(StartError::StartingMPV(error)),
-        };
-        // Ensure the FD lives past the fork.
-        drop(child_socket);
+        if let Some(socket_path) = &self.ipc_server {
+            let mut arg = OsString::from("--input-ipc-server=");
+            arg.push(socket_path);
+            cmd.arg(arg);
+        }
+        for (name, value) in &self.options {
+            cmd.arg(format!("--{}={}", name, value));
+        }
+        match path {
+            // TODO make non-absolute paths to start with "./" so mpv won't parse them as URLs
+            Some(path) => {
+                cmd.arg("--").arg(path);
+            }
+            None => {
+                cmd.arg("--idle=yes").arg("--force-window=yes");
+            }
+        }
+        cmd
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("connecting to MPV: {0}")]
+    Connect(std::io::Error),
+}
 
+impl MPV {
+    /// Attach to an mpv that is already running with `--input-ipc-server=PATH`.
+    ///
+    /// The resulting `MPV` does not own the process; `close` only disconnects, leaving mpv running.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<MPV, ConnectError> {
+        let socket = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(ConnectError::Connect)?;
+        Ok(MPV::from_socket(socket, None))
+    }
+
+    fn from_socket(socket: tokio::net::UnixStream, child: Option<tokio::process::Child>) -> MPV {
         let pending = Arc::new(tokio::sync::Mutex::new(Pending::new() as Pending<IPCResult>));
         let (events_sender, events_receiver) = tokio::sync::broadcast::channel(100);
         let events_sender = Arc::new(events_sender);
@@ -90,12 +203,11 @@ impl Config {
             })
         };
 
-        let mpv = MPV {
+        MPV {
             child,
             ipc,
             ipc_task,
-        };
-        Ok(mpv)
+        }
     }
 }
 
@@ -104,6 +216,11 @@ impl MPV {
         ConfigBuilder::default()
     }
 
+    /// Whether the IPC connection is gone, e.g. because mpv exited. Commands would fail with `IPCError::Disconnected`.
+    pub fn is_disconnected(&self) -> bool {
+        self.ipc.events_sender.upgrade().is_none()
+    }
+
     pub async fn events(&self) -> tokio::sync::broadcast::Receiver<MPVEvent> {
         match self.ipc.events_sender.upgrade() {
             Some(sender) => sender.subscribe(),
@@ -127,6 +244,8 @@ pub enum IPCError {
     Network(std::io::Error),
     #[error("disconnected")]
     Disconnected,
+    #[error("path is not UTF-8")]
+    PathNotUTF8,
 }
 
 // TODO change the Value to avoid unmarshaling to wrong thing, type safety, somehow per-command types
@@ -141,7 +260,13 @@ impl IPCState {
     ) -> tokio::io::Result<()> {
         let reader = tokio::io::BufReader::new(read_socket);
         let mut lines = reader.lines();
-        while let Some(line) = lines.next_line().await? {
+        let result = loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break Ok(()),
+                // Dying with our commands unread makes this ECONNRESET, not EOF; pending commands must still be canceled.
+                Err(error) => break Err(error),
+            };
             match serde_json::from_str::<MPVEnvelope>(&line) {
                 Ok(env) => match env {
                     MPVEnvelope::Event(event) => {
@@ -193,7 +318,7 @@ impl IPCState {
                     debug!(message = "unrecognized mpv message", ?error, json = %line);
                 }
             }
-        }
+        };
 
         // report connection loss to all pending
         {
@@ -202,11 +327,45 @@ impl IPCState {
         }
         drop(events_sender);
 
-        Ok(())
+        result
     }
 }
 
+/// Sends commands to an mpv, without owning it.
+///
+/// Cheap to clone, so callers don't have to hold on to the `MPV` while waiting for mpv to answer.
+/// After mpv goes away, commands fail with `IPCError::Disconnected`.
+/// Holding one keeps our end of the connection open, so don't keep it around.
+#[derive(Clone)]
+pub struct Commander {
+    ipc: Arc<IPCState>,
+}
+
 impl MPV {
+    pub fn commander(&self) -> Commander {
+        Commander {
+            ipc: self.ipc.clone(),
+        }
+    }
+
+    pub async fn command(&self, command: serde_json::Value) -> IPCResult {
+        self.commander().command(command).await
+    }
+
+    /// Replace whatever is playing with `path`.
+    ///
+    /// `options` are set for the duration of this file only, like mpv's per-file options.
+    pub async fn loadfile(&self, path: &OsStr, options: &BTreeMap<String, String>) -> IPCResult {
+        self.commander().loadfile(path, options).await
+    }
+
+    /// Add `path` to the end of the playlist, playing it right away if nothing is playing.
+    pub async fn append(&self, path: &OsStr, options: &BTreeMap<String, String>) -> IPCResult {
+        self.commander().append(path, options).await
+    }
+}
+
+impl Commander {
     pub async fn command(&self, command: serde_json::Value) -> IPCResult {
         let (id_option, receiver) = {
             let mut guard = self.ipc.pending.lock().await;
@@ -238,6 +397,34 @@ impl MPV {
             Ok(result) => result,
         }
     }
+
+    /// Like `MPV::loadfile`.
+    pub async fn loadfile(&self, path: &OsStr, options: &BTreeMap<String, String>) -> IPCResult {
+        self.loadfile_with_flags(path, "replace", options).await
+    }
+
+    /// Like `MPV::append`.
+    pub async fn append(&self, path: &OsStr, options: &BTreeMap<String, String>) -> IPCResult {
+        self.loadfile_with_flags(path, "append-play", options).await
+    }
+
+    async fn loadfile_with_flags(
+        &self,
+        path: &OsStr,
+        flags: &str,
+        options: &BTreeMap<String, String>,
+    ) -> IPCResult {
+        // mpv IPC is JSON, so it can only take UTF-8 paths.
+        let path = path.to_str().ok_or(IPCError::PathNotUTF8)?;
+        // Named arguments, because the positional ones have changed between mpv versions.
+        self.command(serde_json::json!({
+            "name": "loadfile",
+            "url": path,
+            "flags": flags,
+            "options": options,
+        }))
+        .await
+    }
 }
 
 #[derive(Error, Debug)]
@@ -253,14 +440,30 @@ pub enum CloseError {
 }
 
 impl MPV {
-    pub async fn close(mut self) -> Result<(), CloseError> {
+    pub async fn close(self) -> Result<(), CloseError> {
+        let mut child = match self.child {
+            Some(child) => child,
+            None => {
+                // Not ours to stop; hang up and let mpv notice the end of the connection.
+                {
+                    let mut guard = self.ipc.write_socket.lock().await;
+                    guard.shutdown().await.map_err(CloseError::IpcError)?;
+                }
+                self.ipc_task
+                    .await
+                    .map_err(CloseError::TaskError)?
+                    .map_err(CloseError::IpcError)?;
+                return Ok(());
+            }
+        };
+
         // TODO try sending an ipc quit first
 
         // RUST-WART process::Child can't do SIGTERM, idiots. https://github.com/rust-lang/rust/issues/41822
         //
-        // let _ignore_kill_error = self.child.kill();
+        // let _ignore_kill_error = child.kill();
         unsafe {
-            if let Some(id) = self.child.id() {
+            if let Some(id) = child.id() {
                 let _ignore_kill_error = libc::kill(id as i32, libc::SIGTERM);
             }
         }
@@ -269,10 +472,73 @@ impl MPV {
             .await
             .map_err(CloseError::TaskError)?
             .map_err(CloseError::IpcError)?;
-        let exit_status = self.child.wait().await.map_err(CloseError::ProcessError)?;
+        let exit_status = child.wait().await.map_err(CloseError::ProcessError)?;
         if !exit_status.success() {
             return Err(CloseError::MPVExitStatus(exit_status));
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line() {
+        let config = MPV::builder()
+            .fullscreen(false)
+            .executable("/opt/mpv/bin/mpv")
+            .env_var("DISPLAY", ":0")
+            .current_dir("/media")
+            .wrapper(vec![
+                OsString::from("systemd-run"),
+                OsString::from("--scope"),
+            ])
+            .option("volume", "40")
+            .build()
+            .unwrap();
+        let cmd = config.command(Some(OsStr::new("a.mkv")));
+        let cmd = cmd.as_std();
+
+        assert_eq!(cmd.get_program(), "systemd-run");
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "--scope",
+                "/opt/mpv/bin/mpv",
+                "--input-ipc-client=fd://0",
+                "--no-input-terminal",
+                "--volume=40",
+                "--",
+                "a.mkv",
+            ]
+        );
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = cmd.get_envs().collect();
+        assert_eq!(envs, [(OsStr::new("DISPLAY"), Some(OsStr::new(":0")))]);
+        assert_eq!(cmd.get_current_dir(), Some(Path::new("/media")));
+    }
+
+    #[test]
+    fn command_line_defaults() {
+        let config = MPV::builder().build().unwrap();
+        let cmd = config.command(None);
+        let cmd = cmd.as_std();
+
+        assert_eq!(cmd.get_program(), "mpv");
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "--input-ipc-client=fd://0",
+                "--no-input-terminal",
+                "--fullscreen",
+                "--idle=yes",
+                "--force-window=yes",
+            ]
+        );
+        assert_eq!(cmd.get_envs().count(), 0);
+        assert_eq!(cmd.get_current_dir(), None);
+    }
+}
@@ -15,6 +15,24 @@ pub enum PropertyChange {
         #[serde(with = "self::serde_duration")]
         seconds: time::Duration,
     },
+    Pause {
+        #[serde(rename = "data")]
+        paused: bool,
+    },
+    // Unavailable properties come without data, e.g. when no file is loaded.
+    Path {
+        #[serde(rename = "data")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        path: Option<String>,
+    },
+    Duration {
+        #[serde(rename = "data")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        #[serde(with = "self::serde_duration::option")]
+        seconds: Option<time::Duration>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -44,6 +62,12 @@ pub struct MPVEvent {
     event: MPVEventKind,
 }
 
+impl MPVEvent {
+    pub fn kind(&self) -> &MPVEventKind {
+        &self.event
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Response {
     pub request_id: u64,
@@ -132,6 +156,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn event_property_pause() {
+        check(
+            json!({
+                "event":"property-change",
+                "id":1,
+                "name":"pause",
+                "data":true,
+            }),
+            MPVEnvelope::Event(MPVEvent {
+                id: 1,
+                error: None,
+                event: MPVEventKind::PropertyChange(PropertyChange::Pause { paused: true }),
+            }),
+        );
+    }
+
+    #[test]
+    fn event_property_unavailable() {
+        check(
+            json!({
+                "event":"property-change",
+                "id":2,
+                "name":"path",
+            }),
+            MPVEnvelope::Event(MPVEvent {
+                id: 2,
+                error: None,
+                event: MPVEventKind::PropertyChange(PropertyChange::Path { path: None }),
+            }),
+        );
+        check(
+            json!({
+                "event":"property-change",
+                "id":3,
+                "name":"duration",
+                "data":1.5,
+            }),
+            MPVEnvelope::Event(MPVEvent {
+                id: 3,
+                error: None,
+                event: MPVEventKind::PropertyChange(PropertyChange::Duration {
+                    seconds: Some(time::Duration::from_millis(1500)),
+                }),
+            }),
+        );
+    }
+
     #[test]
     fn response_success_bare() {
         check(
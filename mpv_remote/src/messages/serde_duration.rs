@@ -29,3 +29,27 @@ where
         serde::de::Error::invalid_value(serde::de::Unexpected::Float(f), &"seconds as float")
     })
 }
+
+// For properties that may be unavailable.
+pub mod option {
+    use serde::{Deserialize, Serialize};
+    use std::time;
+
+    #[derive(Serialize, Deserialize)]
+    struct Seconds(#[serde(with = "super")] time::Duration);
+
+    pub fn serialize<S>(dur: &Option<time::Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        dur.map(Seconds).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<time::Duration>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let seconds = Option::<Seconds>::deserialize(deserializer)?;
+        Ok(seconds.map(|Seconds(dur)| dur))
+    }
+}
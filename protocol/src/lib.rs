@@ -1,16 +1,343 @@
+//! The HTTP API of choosy, shared by the server and its clients.
+//!
+//! Everything is under `API_PREFIX`; incompatible changes get a new prefix.
+//!
+//! - `GET /search?q=...` returns a `SearchResponse`.
+//! - `GET /browse?path=...` returns a `BrowseResponse`.
+//! - `POST /play` takes a `PlayCommand`.
+//! - `POST /queue` takes a `QueueCommand`.
+//! - `POST /pause` takes a `PauseCommand`.
+//! - `POST /seek` takes a `SeekCommand`.
+//! - `GET /status` returns a `StatusResponse`.
+//! - `POST /login` takes a `LoginRequest`, and sets a session cookie.
+//! - `POST /logout` ends the session, and clears the cookie.
+//! - `GET /openapi.json` describes all of the above.
+//! - `GET /ws` is a WebSocket carrying `ClientMessage`s one way and `ServerMessage`s the other, as JSON text.
+//!
+//! Errors have a status code to match, and an `ErrorResponse` body.
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[cfg(feature = "schema")]
+pub mod openapi;
+
+pub const API_PREFIX: &str = "/api/v1";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SearchResult {
     pub filename: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SearchResponse {
     pub items: Vec<SearchResult>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What is in a directory under the media directory, as far as the database knows.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BrowseResponse {
+    /// Ends in `/`, or is empty for the media directory itself.
+    pub path: String,
+    /// Full paths of the subdirectories, each ending in `/`.
+    pub directories: Vec<String>,
+    pub files: Vec<SearchResult>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct PlayCommand {
     pub filename: String,
 }
+
+/// Play `filename` after what is playing now.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QueueCommand {
+    pub filename: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PauseCommand {
+    /// Pause or resume; toggle if not given.
+    #[serde(default)]
+    pub pause: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SeekCommand {
+    pub seconds: f64,
+    /// Seek from the current position, instead of from the start.
+    #[serde(default)]
+    pub relative: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StatusResponse {
+    /// Nothing, when no file is loaded.
+    pub playing: Option<NowPlaying>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct NowPlaying {
+    /// As in `SearchResult`, when the file is under the media directory.
+    pub filename: String,
+    pub paused: bool,
+    /// In seconds.
+    pub position: Option<f64>,
+    /// In seconds.
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LoginRequest {
+    pub password: String,
+}
+
+/// What went wrong, for programs to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ErrorCode {
+    /// No such file, or it is not allowed to be played.
+    NotFound,
+    /// Something is already playing, and the player is not kept between files.
+    AlreadyPlaying,
+    /// Nothing is playing, to pause or seek.
+    NotPlaying,
+    /// mpv could not be configured, started, or told what to play.
+    PlayerFailed,
+    DatabaseError,
+    /// Log in, or send a bearer token.
+    Unauthorized,
+    /// Refused no matter who is asking, e.g. for a missing CSRF token.
+    Forbidden,
+    /// The request could not be understood.
+    BadRequest,
+    Internal,
+}
+
+/// The body of every error response.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// For humans; not meant to be parsed.
+    pub message: String,
+}
+
+/// A request over the WebSocket.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ClientMessage {
+    /// Chosen by the client, and sent back with the response.
+    /// Responses can arrive out of order; use increasing ids to tell which one is the latest.
+    pub id: u64,
+    pub request: Request,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Request {
+    Search { q: String },
+    Browse { path: String },
+    Play(PlayCommand),
+    Queue(QueueCommand),
+    Pause(PauseCommand),
+    Seek(SeekCommand),
+    Status,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ServerMessage {
+    /// The answer to the `ClientMessage` with the same `id`.
+    Response { id: u64, response: Response },
+    /// Sent whenever what is playing changes, without being asked.
+    Status(StatusResponse),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Response {
+    Search(SearchResponse),
+    Browse(BrowseResponse),
+    Status(StatusResponse),
+    /// The command succeeded.
+    Done,
+    Error(ErrorResponse),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<T>(value: T, json: &str)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+        assert_eq!(serde_json::from_str::<T>(json).unwrap(), value);
+    }
+
+    #[test]
+    fn search() {
+        roundtrip(
+            SearchResponse {
+                items: vec![SearchResult {
+                    filename: "Movies/a.mkv".to_string(),
+                }],
+            },
+            r#"{"items":[{"filename":"Movies/a.mkv"}]}"#,
+        );
+    }
+
+    #[test]
+    fn browse() {
+        roundtrip(
+            BrowseResponse {
+                path: "Movies/".to_string(),
+                directories: vec!["Movies/Old/".to_string()],
+                files: vec![SearchResult {
+                    filename: "Movies/a.mkv".to_string(),
+                }],
+            },
+            r#"{"path":"Movies/","directories":["Movies/Old/"],"files":[{"filename":"Movies/a.mkv"}]}"#,
+        );
+    }
+
+    #[test]
+    fn play() {
+        roundtrip(
+            PlayCommand {
+                filename: "Movies/a.mkv".to_string(),
+            },
+            r#"{"filename":"Movies/a.mkv"}"#,
+        );
+    }
+
+    #[test]
+    fn control() {
+        roundtrip(
+            QueueCommand {
+                filename: "Movies/b.mkv".to_string(),
+            },
+            r#"{"filename":"Movies/b.mkv"}"#,
+        );
+        roundtrip(PauseCommand { pause: Some(true) }, r#"{"pause":true}"#);
+        roundtrip(PauseCommand { pause: None }, r#"{"pause":null}"#);
+        assert_eq!(
+            serde_json::from_str::<PauseCommand>("{}").unwrap(),
+            PauseCommand { pause: None }
+        );
+        roundtrip(
+            SeekCommand {
+                seconds: -10.5,
+                relative: true,
+            },
+            r#"{"seconds":-10.5,"relative":true}"#,
+        );
+    }
+
+    #[test]
+    fn status() {
+        roundtrip(StatusResponse { playing: None }, r#"{"playing":null}"#);
+        roundtrip(
+            StatusResponse {
+                playing: Some(NowPlaying {
+                    filename: "Movies/a.mkv".to_string(),
+                    paused: false,
+                    position: Some(12.5),
+                    duration: None,
+                }),
+            },
+            r#"{"playing":{"filename":"Movies/a.mkv","paused":false,"position":12.5,"duration":null}}"#,
+        );
+    }
+
+    #[test]
+    fn login() {
+        roundtrip(
+            LoginRequest {
+                password: "sekrit".to_string(),
+            },
+            r#"{"password":"sekrit"}"#,
+        );
+    }
+
+    #[test]
+    fn error() {
+        roundtrip(
+            ErrorResponse {
+                code: ErrorCode::AlreadyPlaying,
+                message: "already playing".to_string(),
+            },
+            r#"{"code":"AlreadyPlaying","message":"already playing"}"#,
+        );
+        // Clients match on these, so they must not change.
+        for (code, json) in [
+            (ErrorCode::NotFound, r#""NotFound""#),
+            (ErrorCode::AlreadyPlaying, r#""AlreadyPlaying""#),
+            (ErrorCode::NotPlaying, r#""NotPlaying""#),
+            (ErrorCode::PlayerFailed, r#""PlayerFailed""#),
+            (ErrorCode::DatabaseError, r#""DatabaseError""#),
+            (ErrorCode::Unauthorized, r#""Unauthorized""#),
+            (ErrorCode::Forbidden, r#""Forbidden""#),
+            (ErrorCode::BadRequest, r#""BadRequest""#),
+            (ErrorCode::Internal, r#""Internal""#),
+        ] {
+            roundtrip(code, json);
+        }
+    }
+
+    #[test]
+    fn websocket() {
+        roundtrip(
+            ClientMessage {
+                id: 7,
+                request: Request::Search {
+                    q: "star".to_string(),
+                },
+            },
+            r#"{"id":7,"request":{"Search":{"q":"star"}}}"#,
+        );
+        roundtrip(
+            ClientMessage {
+                id: 8,
+                request: Request::Pause(PauseCommand { pause: None }),
+            },
+            r#"{"id":8,"request":{"Pause":{"pause":null}}}"#,
+        );
+        roundtrip(
+            ClientMessage {
+                id: 9,
+                request: Request::Status,
+            },
+            r#"{"id":9,"request":"Status"}"#,
+        );
+        roundtrip(
+            ServerMessage::Response {
+                id: 7,
+                response: Response::Search(SearchResponse { items: vec![] }),
+            },
+            r#"{"Response":{"id":7,"response":{"Search":{"items":[]}}}}"#,
+        );
+        roundtrip(
+            ServerMessage::Response {
+                id: 8,
+                response: Response::Done,
+            },
+            r#"{"Response":{"id":8,"response":"Done"}}"#,
+        );
+        roundtrip(
+            ServerMessage::Status(StatusResponse { playing: None }),
+            r#"{"Status":{"playing":null}}"#,
+        );
+    }
+}
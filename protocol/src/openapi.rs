@@ -0,0 +1,224 @@
+//! An OpenAPI 3.0 description of the API, built from the protocol types.
+
+use crate::{
+    BrowseResponse, ClientMessage, ErrorResponse, LoginRequest, PauseCommand, PlayCommand,
+    QueueCommand, SearchResponse, SeekCommand, ServerMessage, StatusResponse, API_PREFIX,
+};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+fn reference<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).expect("schemas must serialize")
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({
+        "content": {
+            "application/json": { "schema": schema },
+        },
+    })
+}
+
+/// The OpenAPI document, as JSON.
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let search_response = reference::<SearchResponse>(&mut gen);
+    let browse_response = reference::<BrowseResponse>(&mut gen);
+    let play_command = reference::<PlayCommand>(&mut gen);
+    let queue_command = reference::<QueueCommand>(&mut gen);
+    let pause_command = reference::<PauseCommand>(&mut gen);
+    let seek_command = reference::<SeekCommand>(&mut gen);
+    let status_response = reference::<StatusResponse>(&mut gen);
+    let login_request = reference::<LoginRequest>(&mut gen);
+    let error_response = reference::<ErrorResponse>(&mut gen);
+    // Only referenced from the description of `/ws`; OpenAPI cannot describe WebSocket messages.
+    reference::<ClientMessage>(&mut gen);
+    reference::<ServerMessage>(&mut gen);
+
+    let error = json!({
+        "description": "Error; see `code`",
+        "content": {
+            "application/json": { "schema": error_response },
+        },
+    });
+    let csrf = json!({
+        "name": "X-CSRF-Token",
+        "in": "header",
+        "description": "Value of the `choosy_csrf` cookie; not needed with a bearer token, or without cookies and `Origin`",
+        "schema": { "type": "string" },
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "choosy",
+            "description": "Choose a file and play it with mpv",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": API_PREFIX }],
+        "paths": {
+            "/search": {
+                "get": {
+                    "summary": "Find media by words in the file name",
+                    "parameters": [{
+                        "name": "q",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": json_body(search_response),
+                        "default": error,
+                    },
+                },
+            },
+            "/browse": {
+                "get": {
+                    "summary": "List a directory",
+                    "parameters": [{
+                        "name": "path",
+                        "in": "query",
+                        "description": "Relative to the media directory; empty for the top",
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": json_body(browse_response),
+                        "default": error,
+                    },
+                },
+            },
+            "/play": {
+                "post": {
+                    "summary": "Play a file from the search results",
+                    "parameters": [csrf],
+                    "requestBody": json_body(play_command),
+                    "responses": {
+                        "200": { "description": "Playing" },
+                        "default": error,
+                    },
+                },
+            },
+            "/queue": {
+                "post": {
+                    "summary": "Play a file after the one playing now",
+                    "parameters": [csrf],
+                    "requestBody": json_body(queue_command),
+                    "responses": {
+                        "200": { "description": "Queued" },
+                        "default": error,
+                    },
+                },
+            },
+            "/pause": {
+                "post": {
+                    "summary": "Pause or resume",
+                    "parameters": [csrf],
+                    "requestBody": json_body(pause_command),
+                    "responses": {
+                        "200": { "description": "Done" },
+                        "default": error,
+                    },
+                },
+            },
+            "/seek": {
+                "post": {
+                    "summary": "Move within the file playing now",
+                    "parameters": [csrf],
+                    "requestBody": json_body(seek_command),
+                    "responses": {
+                        "200": { "description": "Done" },
+                        "default": error,
+                    },
+                },
+            },
+            "/status": {
+                "get": {
+                    "summary": "What is playing",
+                    "responses": {
+                        "200": json_body(status_response),
+                        "default": error,
+                    },
+                },
+            },
+            "/ws": {
+                "get": {
+                    "summary": "WebSocket for search, control and player status",
+                    "description": "Send `ClientMessage`s and receive `ServerMessage`s, as JSON text messages.",
+                    "responses": {
+                        "101": { "description": "Switching to WebSocket" },
+                        "default": error,
+                    },
+                },
+            },
+            "/login": {
+                "post": {
+                    "summary": "Log in with the password, setting the `choosy_session` cookie",
+                    "parameters": [csrf],
+                    "requestBody": json_body(login_request),
+                    "responses": {
+                        "200": { "description": "Logged in" },
+                        "default": error,
+                    },
+                },
+            },
+            "/logout": {
+                "post": {
+                    "summary": "End the session, clearing the `choosy_session` cookie",
+                    "parameters": [csrf],
+                    "responses": {
+                        "200": { "description": "Logged out" },
+                        "default": error,
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": "choosy_session" },
+                "token": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "security": [{ "session": [] }, { "token": [] }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schemas() {
+        let document = document();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let mut names: Vec<&str> = schemas.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "BrowseResponse",
+                "ClientMessage",
+                "ErrorCode",
+                "ErrorResponse",
+                "LoginRequest",
+                "NowPlaying",
+                "PauseCommand",
+                "PlayCommand",
+                "QueueCommand",
+                "Request",
+                "Response",
+                "SearchResponse",
+                "SearchResult",
+                "SeekCommand",
+                "ServerMessage",
+                "StatusResponse",
+            ]
+        );
+        assert_eq!(
+            document["paths"]["/play"]["post"]["requestBody"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            "#/components/schemas/PlayCommand"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Subcommands for maintenance from the command line, instead of serving the web UI.
+//!
+//! They open the database directly, so they cannot run while the server has it open.
+
+use crate::config::Config;
+use crate::database::{self, MediaDb};
+use crate::file_scanner;
+use anyhow::{bail, Context};
+use std::io::Write;
+use std::path::Path;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+#[derive(structopt::StructOpt, Debug)]
+pub enum Command {
+    /// Write the media database to stdout, as JSON Lines
+    Export,
+    /// Read media database entries from stdin, as written by export
+    Import,
+    /// Inspect and edit the media database
+    Db(DbCommand),
+    /// Scan the media directory into the database, like the server does
+    Scan {
+        /// Scan once and exit, instead of repeating forever
+        #[structopt(long)]
+        once: bool,
+    },
+    /// Inspect the configuration
+    Config(ConfigCommand),
+}
+
+#[derive(structopt::StructOpt, Debug)]
+pub enum DbCommand {
+    /// List all media, as flags `e` for exists and `w` for watched, and the path
+    List,
+    /// Show one item, as JSON
+    Get { path: String },
+    /// Mark media as watched
+    SetWatched {
+        path: String,
+        /// Mark as not watched, instead
+        #[structopt(long)]
+        unwatched: bool,
+    },
+    /// Remove media from the database; it comes back if the scanner still finds it
+    Forget { path: String },
+    /// Rewrite media stored in an older format, after an upgrade; old formats are still read without this
+    Migrate,
+    /// Copy media from one tree of the database into another, re-encoded, e.g. as JSON for debugging
+    Convert {
+        /// Tree to copy from
+        #[structopt(long, default_value = "media")]
+        from: String,
+        /// Encoding of the tree to copy from: bincode, json or cbor
+        #[structopt(long, default_value = "bincode")]
+        from_encoding: EncodingName,
+        /// Tree to copy into; it must be empty
+        #[structopt(long)]
+        to: String,
+        /// Encoding to copy into: bincode, json or cbor
+        #[structopt(long)]
+        to_encoding: EncodingName,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EncodingName {
+    Bincode,
+    Json,
+    Cbor,
+}
+
+impl std::str::FromStr for EncodingName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(EncodingName::Bincode),
+            "json" => Ok(EncodingName::Json),
+            "cbor" => Ok(EncodingName::Cbor),
+            _ => Err(format!("unknown encoding: {}", s)),
+        }
+    }
+}
+
+#[derive(structopt::StructOpt, Debug)]
+pub enum ConfigCommand {
+    /// Load the configuration and report problems
+    Check,
+}
+
+fn open_media(database: &Path) -> Result<(sled::Db, MediaDb), anyhow::Error> {
+    let db = sled::open(database).context("error opening database")?;
+    let media = database::open_media(&db)?;
+    Ok((db, media))
+}
+
+fn load_config(config: Option<&Path>) -> Result<Config, anyhow::Error> {
+    let path = config.ok_or_else(|| anyhow::anyhow!("--config is required for this command"))?;
+    let config = Config::load(path).context("error loading config file")?;
+    Ok(config)
+}
+
+pub fn run(command: Command, config: Option<&Path>, database: &Path) -> Result<(), anyhow::Error> {
+    match command {
+        Command::Export => {
+            let (_db, media) = open_media(database)?;
+            let stdout = std::io::stdout();
+            let count = database::backup::export(&media, stdout.lock())
+                .context("error exporting database")?;
+            info!(message = "exported media database", count);
+        }
+        Command::Import => {
+            let (db, media) = open_media(database)?;
+            let stdin = std::io::stdin();
+            let count = database::backup::import(&media, stdin.lock())
+                .context("error importing database")?;
+            db.flush().context("error flushing database")?;
+            info!(message = "imported media database", count);
+        }
+        Command::Db(command) => {
+            let db = sled::open(database).context("error opening database")?;
+            run_db(command, &db)?;
+            db.flush().context("error flushing database")?;
+        }
+        Command::Scan { once } => {
+            let config = load_config(config)?;
+            let (db, media) = open_media(database)?;
+            loop {
+                file_scanner::scan_once(Path::new(&config.path), &media);
+                db.flush().context("error flushing database")?;
+                if once {
+                    break;
+                }
+                std::thread::sleep(file_scanner::RESCAN_INTERVAL);
+            }
+        }
+        Command::Config(ConfigCommand::Check) => {
+            let config = load_config(config)?;
+            check_config(&config)?;
+            println!("config ok");
+        }
+    }
+    Ok(())
+}
+
+fn run_db(command: DbCommand, db: &sled::Db) -> Result<(), anyhow::Error> {
+    let media = database::open_media(db)?;
+    match command {
+        DbCommand::List => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            for result in media.iter() {
+                let (path, item) = result.context("error reading database")?;
+                writeln!(
+                    out,
+                    "{}{} {}",
+                    if item.exists { 'e' } else { '-' },
+                    if item.watched { 'w' } else { '-' },
+                    path
+                )?;
+            }
+        }
+        DbCommand::Get { path } => {
+            let item = match media.get(&path).context("error reading database")? {
+                None => bail!("not in database: {}", path),
+                Some(item) => item,
+            };
+            let record = database::backup::Record::new(path, item);
+            println!("{}", serde_json::to_string(&record)?);
+        }
+        DbCommand::SetWatched { path, unwatched } => {
+            // Merging into a missing item would create it.
+            if media
+                .get(&path)
+                .context("error reading database")?
+                .is_none()
+            {
+                bail!("not in database: {}", path);
+            }
+            media
+                .merge(&path, &vec![database::media::Op::Watched(!unwatched)])
+                .context("error writing database")?;
+        }
+        DbCommand::Forget { path } => {
+            if media
+                .get(&path)
+                .context("error reading database")?
+                .is_none()
+            {
+                bail!("not in database: {}", path);
+            }
+            media.remove(&path).context("error writing database")?;
+        }
+        DbCommand::Convert {
+            from,
+            from_encoding,
+            to,
+            to_encoding,
+        } => {
+            // Not through `media`, which knows only its own encoding.
+            if !db.tree_names().iter().any(|name| name == from.as_bytes()) {
+                bail!("no such tree: {}", from);
+            }
+            let from = db.open_tree(from).context("error opening tree")?;
+            let to = db.open_tree(to).context("error opening tree")?;
+            if !to.is_empty() {
+                bail!("tree to copy into is not empty");
+            }
+            let count = match from_encoding {
+                EncodingName::Bincode => {
+                    convert::<sleigh::encoding::Bincode>(&from, &to, to_encoding)
+                }
+                EncodingName::Json => convert::<sleigh::encoding::Json>(&from, &to, to_encoding),
+                EncodingName::Cbor => convert::<sleigh::encoding::Cbor>(&from, &to, to_encoding),
+            }?;
+            info!(message = "converted media", count);
+        }
+        DbCommand::Migrate => {
+            let count = media
+                .rewrite_all()
+                .context("error migrating media database")?;
+            info!(message = "migrated media database", count);
+        }
+    }
+    Ok(())
+}
+
+fn convert<FromEnc>(
+    from: &sled::Tree,
+    to: &sled::Tree,
+    to_encoding: EncodingName,
+) -> Result<u64, anyhow::Error>
+where
+    FromEnc: sleigh::encoding::Encoding,
+    FromEnc::Error: Send + Sync + 'static,
+{
+    use database::media::Media;
+    use sleigh::encoding::{convert, Bincode, Cbor, Json};
+
+    let count = match to_encoding {
+        EncodingName::Bincode => convert::<Media, FromEnc, Bincode>(from, to)?,
+        EncodingName::Json => convert::<Media, FromEnc, Json>(from, to)?,
+        EncodingName::Cbor => convert::<Media, FromEnc, Cbor>(from, to)?,
+    };
+    Ok(count)
+}
+
+fn check_config(config: &Config) -> Result<(), anyhow::Error> {
+    let path = Path::new(&config.path);
+    if !path.is_dir() {
+        bail!("media path is not a directory: {}", config.path);
+    }
+    config
+        .mpv_builder()
+        .build()
+        .context("error configuring mpv")?;
+    Ok(())
+}
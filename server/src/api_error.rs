@@ -0,0 +1,163 @@
+//! Errors from the API handlers, sent to the client as a `proto::ErrorResponse`.
+//!
+//! `ApiJson` and `ApiQuery` extract like axum's `Json` and `Query`, but reject with one too.
+
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use choosy_protocol as proto;
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: proto::ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: proto::ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        use proto::ErrorCode::*;
+        match self.code {
+            NotFound => StatusCode::NOT_FOUND,
+            AlreadyPlaying | NotPlaying => StatusCode::CONFLICT,
+            PlayerFailed | DatabaseError | Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Unauthorized => StatusCode::UNAUTHORIZED,
+            Forbidden => StatusCode::FORBIDDEN,
+            BadRequest => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+// `auth` only decides between a few statuses.
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ApiError::new(
+                proto::ErrorCode::Unauthorized,
+                "log in, or send a bearer token",
+            ),
+            StatusCode::FORBIDDEN => ApiError::new(
+                proto::ErrorCode::Forbidden,
+                "refused; check the Host and Origin headers, and the CSRF token",
+            ),
+            _ => ApiError::new(
+                proto::ErrorCode::Internal,
+                status.canonical_reason().unwrap_or("error"),
+            ),
+        }
+    }
+}
+
+impl From<ApiError> for proto::ErrorResponse {
+    fn from(error: ApiError) -> Self {
+        proto::ErrorResponse {
+            code: error.code,
+            message: error.message,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let body = proto::ErrorResponse::from(self);
+        (status, Json(body)).into_response()
+    }
+}
+
+fn bad_request(rejection: impl std::fmt::Display) -> ApiError {
+    ApiError::new(proto::ErrorCode::BadRequest, rejection.to_string())
+}
+
+pub struct ApiJson<T>(pub T);
+
+#[axum::async_trait]
+impl<T, B> FromRequest<B> for ApiJson<T>
+where
+    Json<T>: FromRequest<B>,
+    <Json<T> as FromRequest<B>>::Rejection: std::fmt::Display,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::from_request(req).await.map_err(bad_request)?;
+        Ok(ApiJson(value))
+    }
+}
+
+pub struct ApiQuery<T>(pub T);
+
+#[axum::async_trait]
+impl<T, B> FromRequest<B> for ApiQuery<T>
+where
+    axum::extract::Query<T>: FromRequest<B>,
+    <axum::extract::Query<T> as FromRequest<B>>::Rejection: std::fmt::Display,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) = axum::extract::Query::from_request(req)
+            .await
+            .map_err(bad_request)?;
+        Ok(ApiQuery(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn json_rejection() {
+        let request = axum::http::Request::post("/play")
+            .header("content-type", "application/json")
+            .body(hyper::Body::from("{"))
+            .unwrap();
+        let mut parts = RequestParts::new(request);
+        match ApiJson::<proto::PlayCommand>::from_request(&mut parts).await {
+            Ok(_) => panic!("truncated JSON must not parse"),
+            Err(error) => assert_eq!(error.code, proto::ErrorCode::BadRequest),
+        }
+    }
+
+    #[tokio::test]
+    async fn query_rejection() {
+        #[derive(serde::Deserialize)]
+        struct Search {
+            #[allow(dead_code)]
+            q: String,
+        }
+
+        let request = axum::http::Request::get("/search")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let mut parts = RequestParts::new(request);
+        match ApiQuery::<Search>::from_request(&mut parts).await {
+            Ok(_) => panic!("missing q must not parse"),
+            Err(error) => {
+                assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+                assert_eq!(error.code, proto::ErrorCode::BadRequest);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn response() {
+        let error = ApiError::new(proto::ErrorCode::AlreadyPlaying, "already playing");
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: proto::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, proto::ErrorCode::AlreadyPlaying);
+        assert_eq!(body.message, "already playing");
+    }
+}
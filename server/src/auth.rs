@@ -0,0 +1,515 @@
+//! Who may use the API: `Host`/`Origin` checks, optional login, and CSRF tokens.
+//!
+//! Browsers get a session cookie by logging in with the password, and a CSRF cookie with the page.
+//! Mutating requests from browsers must echo the CSRF cookie in the `X-CSRF-Token` header; a page from another origin cannot read the cookie to do that.
+//! Requests with neither cookies nor an `Origin` are not from a browser, and have nothing a forged request could ride on, so they skip the CSRF check.
+//! Browsers cannot add headers to WebSocket requests, so those skip the CSRF header but must have an allowed `Origin`, which browsers always send with them.
+//! Scripts can skip both by sending `Authorization: Bearer <token>`.
+//!
+//! Sessions are only kept in memory, so restarting choosy logs everyone out.
+//! They also expire after `SESSION_LIFETIME`, or on logging out.
+
+use crate::config::Config;
+use axum::http::header::{self, HeaderMap, HeaderValue};
+use axum::http::StatusCode;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+pub const SESSION_COOKIE: &str = "choosy_session";
+pub const CSRF_COOKIE: &str = "choosy_csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// Always allowed, in addition to `Config::allowed_hosts`.
+const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Opening a WebSocket, which can then be used for writes.
+    WebSocket,
+}
+
+pub struct Auth {
+    password: Option<String>,
+    tokens: Vec<String>,
+    allowed_hosts: Vec<String>,
+    // When each session was started.
+    sessions: Mutex<HashMap<String, Instant>>,
+    session_lifetime: Duration,
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Self {
+        let mut allowed_hosts: Vec<String> = LOCAL_HOSTS.iter().map(|s| s.to_string()).collect();
+        allowed_hosts.extend(config.allowed_hosts.iter().map(|s| s.to_ascii_lowercase()));
+        let (password, tokens) = match &config.auth {
+            None => (None, Vec::new()),
+            Some(auth) => (Some(auth.password.clone()), auth.tokens.clone()),
+        };
+        Auth {
+            password,
+            tokens,
+            allowed_hosts,
+            sessions: Mutex::new(HashMap::new()),
+            session_lifetime: SESSION_LIFETIME,
+        }
+    }
+
+    /// Decide whether a request with these headers may go on.
+    pub fn check(&self, headers: &HeaderMap, access: Access) -> Result<(), StatusCode> {
+        self.check_host(headers)?;
+
+        if let Some(token) = bearer_token(headers) {
+            if self.tokens.iter().any(|t| constant_time_eq(t, token)) {
+                // Not a browser riding on cookies, so no CSRF to worry about.
+                return Ok(());
+            }
+            warn!("bad bearer token");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        if self.password.is_some() {
+            let logged_in = match cookie(headers, SESSION_COOKIE) {
+                None => false,
+                Some(session) => match self.sessions.lock().unwrap().get(session) {
+                    None => false,
+                    Some(started) => started.elapsed() < self.session_lifetime,
+                },
+            };
+            if !logged_in {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+
+        match access {
+            Access::Read => (),
+            Access::Write => {
+                if from_browser(headers) {
+                    check_csrf(headers)?;
+                }
+            }
+            // `check_host` already made sure any `Origin` is ours.
+            Access::WebSocket => {
+                if !headers.contains_key(header::ORIGIN) {
+                    warn!("refusing WebSocket without origin");
+                    return Err(StatusCode::FORBIDDEN);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Log in with `password`, returning the `Set-Cookie` value for a new session.
+    pub fn login(&self, headers: &HeaderMap, password: &str) -> Result<HeaderValue, StatusCode> {
+        self.check_host(headers)?;
+        // Otherwise another site could log the browser into its own session.
+        check_csrf(headers)?;
+        let expected = match &self.password {
+            // Nothing to log in to; the session cookie is never looked at.
+            None => "",
+            Some(expected) => expected,
+        };
+        if !constant_time_eq(expected, password) {
+            warn!("bad password");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let session = random_token();
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+            SESSION_COOKIE,
+            session,
+            self.session_lifetime.as_secs()
+        );
+        let mut sessions = self.sessions.lock().unwrap();
+        // Forget the expired ones, so the map does not grow forever.
+        sessions.retain(|_, started| started.elapsed() < self.session_lifetime);
+        sessions.insert(session, Instant::now());
+        HeaderValue::from_str(&cookie).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// End the session in the request, if any, returning the `Set-Cookie` value that removes it.
+    pub fn logout(&self, headers: &HeaderMap) -> Result<HeaderValue, StatusCode> {
+        self.check_host(headers)?;
+        check_csrf(headers)?;
+        if let Some(session) = cookie(headers, SESSION_COOKIE) {
+            self.sessions.lock().unwrap().remove(session);
+        }
+        let cookie = format!(
+            "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+            SESSION_COOKIE
+        );
+        HeaderValue::from_str(&cookie).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn check_host(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .map(split_port);
+        let (host, port) = match host {
+            Some((host, port)) if self.is_allowed(host) => (host, port),
+            _ => {
+                warn!(message = "refusing request for unknown host", ?host);
+                return Err(StatusCode::FORBIDDEN);
+            }
+        };
+        if let Some(origin) = headers.get(header::ORIGIN) {
+            // Only pages from this very server; another port on the same host is another site, but could read our cookies.
+            let same_origin = match origin.to_str().ok().and_then(parse_origin) {
+                None => false,
+                Some((origin_host, origin_port, default_port)) => {
+                    origin_host.eq_ignore_ascii_case(host)
+                        && origin_port == port.unwrap_or(default_port)
+                }
+            };
+            if !same_origin {
+                warn!(message = "refusing request from another origin", ?origin);
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+}
+
+/// The `Set-Cookie` value for a new CSRF token, if the browser has none yet.
+pub fn csrf_cookie(headers: &HeaderMap) -> Option<HeaderValue> {
+    if cookie(headers, CSRF_COOKIE).is_some() {
+        return None;
+    }
+    // Not HttpOnly; the frontend reads it to send it back in the header.
+    let cookie = format!(
+        "{}={}; Path=/; SameSite=Strict",
+        CSRF_COOKIE,
+        random_token()
+    );
+    HeaderValue::from_str(&cookie).ok()
+}
+
+fn check_csrf(headers: &HeaderMap) -> Result<(), StatusCode> {
+    let sent = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (sent, cookie(headers, CSRF_COOKIE)) {
+        (Some(sent), Some(expected))
+            if !expected.is_empty() && constant_time_eq(sent, expected) =>
+        {
+            Ok(())
+        }
+        _ => {
+            warn!("missing or wrong CSRF token");
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+// Browsers send cookies with forged requests, and `Origin` with any cross-site POST.
+// Scripts like `choosy-cli` send neither.
+fn from_browser(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::COOKIE) || headers.contains_key(header::ORIGIN)
+}
+
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// `localhost:8000` -> `localhost` and `8000`, `[::1]` -> `[::1]` and no port.
+// A port that is not a number is left as part of the host, which then won't be allowed.
+fn split_port(host: &str) -> (&str, Option<u16>) {
+    let name_end = match host.starts_with('[') {
+        true => host.find(']').map_or(host.len(), |end| end + 1),
+        false => host.rfind(':').unwrap_or(host.len()),
+    };
+    match host[name_end..].strip_prefix(':') {
+        None => (host, None),
+        Some(port) => match port.parse() {
+            Ok(port) => (&host[..name_end], Some(port)),
+            Err(_) => (host, None),
+        },
+    }
+}
+
+// `http://localhost:8000` -> `localhost`, `8000`, and the default port of the scheme, `80`.
+fn parse_origin(origin: &str) -> Option<(&str, u16, u16)> {
+    let (scheme, authority) = origin.split_once("://")?;
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let (host, port) = split_port(authority);
+    Some((host, port.unwrap_or(default_port), default_port))
+}
+
+// Don't let response times tell how much of a secret was guessed right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(extra: &str) -> Auth {
+        let config: Config =
+            ron::de::from_str(&format!(r#"ChoosyConfig(path: "/media", {})"#, extra)).unwrap();
+        Auth::new(&config)
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn host_and_origin() {
+        let auth = auth(r#"allowed_hosts: ["media.lan"]"#);
+        let ok = |pairs: &[(&'static str, &str)]| auth.check(&headers(pairs), Access::Read);
+        assert_eq!(ok(&[("host", "localhost:8000")]), Ok(()));
+        assert_eq!(ok(&[("host", "[::1]:8000")]), Ok(()));
+        assert_eq!(ok(&[("host", "MEDIA.lan")]), Ok(()));
+        assert_eq!(ok(&[]), Err(StatusCode::FORBIDDEN));
+        assert_eq!(
+            ok(&[("host", "evil.example:8000")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ok(&[
+                ("host", "localhost:8000"),
+                ("origin", "http://localhost:8000")
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            ok(&[("host", "media.lan"), ("origin", "https://media.lan")]),
+            Ok(())
+        );
+        assert_eq!(
+            ok(&[("host", "media.lan:443"), ("origin", "https://MEDIA.lan")]),
+            Ok(())
+        );
+        assert_eq!(
+            ok(&[("host", "localhost"), ("origin", "https://evil.example")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        // Another site on the same host.
+        assert_eq!(
+            ok(&[
+                ("host", "localhost:8000"),
+                ("origin", "http://localhost:9000")
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ok(&[("host", "localhost"), ("origin", "http://localhost:8000")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ok(&[("host", "media.lan"), ("origin", "http://localhost")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ok(&[
+                ("host", "localhost:8000"),
+                ("origin", "ftp://localhost:8000")
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            ok(&[("host", "localhost"), ("origin", "null")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn csrf() {
+        let auth = auth("");
+        let check = |pairs: &[(&'static str, &str)]| auth.check(&headers(pairs), Access::Write);
+        assert_eq!(
+            check(&[
+                ("host", "localhost"),
+                ("cookie", "a=b; choosy_csrf=t0k"),
+                ("x-csrf-token", "t0k"),
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&[("host", "localhost"), ("cookie", "choosy_csrf=t0k")]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            check(&[
+                ("host", "localhost"),
+                ("cookie", "choosy_csrf=t0k"),
+                ("x-csrf-token", "other"),
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            check(&[
+                ("host", "localhost:8000"),
+                ("origin", "http://localhost:8000")
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        // Not a browser, so there is no cookie to ride on.
+        assert_eq!(check(&[("host", "localhost")]), Ok(()));
+        assert!(csrf_cookie(&headers(&[("cookie", "choosy_csrf=t0k")])).is_none());
+        assert!(csrf_cookie(&headers(&[])).is_some());
+    }
+
+    #[test]
+    fn websocket() {
+        let auth = auth(r#"auth: Some((password: "sekrit", tokens: ["scripted"]))"#);
+        let check = |pairs: &[(&'static str, &str)]| auth.check(&headers(pairs), Access::WebSocket);
+        assert_eq!(
+            check(&[("host", "localhost"), ("authorization", "Bearer scripted")]),
+            Ok(())
+        );
+        let csrf = [
+            ("host", "localhost"),
+            ("cookie", "choosy_csrf=t0k"),
+            ("x-csrf-token", "t0k"),
+        ];
+        let set_cookie = auth.login(&headers(&csrf), "sekrit").unwrap();
+        let session = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        assert_eq!(
+            check(&[
+                ("host", "localhost:8000"),
+                ("origin", "http://localhost:8000"),
+                ("cookie", session),
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            check(&[
+                ("host", "localhost:8000"),
+                ("origin", "http://localhost:9000"),
+                ("cookie", session),
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            check(&[("host", "localhost"), ("cookie", session)]),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            check(&[
+                ("host", "localhost"),
+                ("origin", "https://evil.example"),
+                ("cookie", session),
+            ]),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn login() {
+        let auth = auth(r#"auth: Some((password: "sekrit", tokens: ["scripted"]))"#);
+        let csrf = [
+            ("host", "localhost"),
+            ("cookie", "choosy_csrf=t0k"),
+            ("x-csrf-token", "t0k"),
+        ];
+        assert_eq!(
+            auth.check(&headers(&csrf), Access::Read),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            auth.login(&headers(&csrf), "wrong"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let set_cookie = auth.login(&headers(&csrf), "sekrit").unwrap();
+        let session = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        let cookies = format!("choosy_csrf=t0k; {}", session);
+        let logged_in = [
+            ("host", "localhost"),
+            ("cookie", &cookies),
+            ("x-csrf-token", "t0k"),
+        ];
+        assert_eq!(auth.check(&headers(&logged_in), Access::Write), Ok(()));
+
+        let scripted = [("host", "localhost"), ("authorization", "Bearer scripted")];
+        assert_eq!(auth.check(&headers(&scripted), Access::Write), Ok(()));
+        let bad_token = [("host", "localhost"), ("authorization", "Bearer nope")];
+        assert_eq!(
+            auth.check(&headers(&bad_token), Access::Read),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let set_cookie = auth.logout(&headers(&logged_in)).unwrap();
+        assert!(set_cookie.to_str().unwrap().contains("Max-Age=0"));
+        assert_eq!(
+            auth.check(&headers(&logged_in), Access::Write),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn session_expires() {
+        let mut auth = auth(r#"auth: Some((password: "sekrit"))"#);
+        auth.session_lifetime = Duration::ZERO;
+        let csrf = [
+            ("host", "localhost"),
+            ("cookie", "choosy_csrf=t0k"),
+            ("x-csrf-token", "t0k"),
+        ];
+        let set_cookie = auth.login(&headers(&csrf), "sekrit").unwrap();
+        let session = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        assert_eq!(
+            auth.check(
+                &headers(&[("host", "localhost"), ("cookie", session)]),
+                Access::Read
+            ),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        // Logging in again cleans it out.
+        auth.login(&headers(&csrf), "sekrit").unwrap();
+        assert_eq!(auth.sessions.lock().unwrap().len(), 1);
+    }
+}
@@ -1,4 +1,6 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::File;
 use std::path::Path;
 use thiserror::Error;
@@ -10,12 +12,79 @@ pub struct Config {
     /// Whether the player should be fullscreen or not.
     #[serde(default = "default_true")]
     pub fullscreen: bool,
+    /// Extra mpv options used for every file, e.g. `{"volume": "50"}`.
+    #[serde(default)]
+    pub mpv_options: BTreeMap<String, String>,
+    /// Options for files matching a glob, applied in order on top of `mpv_options`.
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    /// The mpv executable, looked up in `PATH` unless it contains a slash.
+    #[serde(default)]
+    pub mpv_executable: Option<String>,
+    /// Extra environment variables for mpv, e.g. `{"DISPLAY": ":0"}`.
+    #[serde(default)]
+    pub mpv_env: BTreeMap<String, String>,
+    /// Working directory for mpv.
+    #[serde(default)]
+    pub mpv_working_directory: Option<String>,
+    /// Command and arguments to run mpv under, e.g. `["systemd-run", "--user", "--scope"]`.
+    #[serde(default)]
+    pub mpv_wrapper: Vec<String>,
+    /// Keep one idle mpv running and load every file into it, instead of starting mpv for each.
+    #[serde(default)]
+    pub keep_player: bool,
+    /// Unix socket where mpv listens for IPC.
+    /// With `keep_player`, a restarted choosy attaches to the mpv found there.
+    #[serde(default)]
+    pub mpv_socket: Option<String>,
+    /// Play files through symlinks that lead outside `path`.
+    #[serde(default)]
+    pub follow_external_symlinks: bool,
+    /// Require logging in, for when choosy is reachable by more than localhost.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Host names the server may be reached by, in addition to localhost.
+    /// Requests with any other `Host` or `Origin` are refused, to stop DNS rebinding.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+    /// Password for logging in from the browser.
+    pub password: String,
+    /// Tokens for scripts, sent as `Authorization: Bearer <token>`.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Profile {
+    /// Glob matched against the path relative to `Config::path`.
+    /// `*` does not match `/`, use `**` to match across directories.
+    #[serde(deserialize_with = "deserialize_glob")]
+    pub glob: globset::GlobMatcher,
+    /// mpv options for matching files, on top of `Config::mpv_options`.
+    /// Where several profiles match, later ones override earlier ones.
+    pub options: BTreeMap<String, String>,
 }
 
 fn default_true() -> bool {
     true
 }
 
+fn deserialize_glob<'de, D>(deserializer: D) -> Result<globset::GlobMatcher, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    let glob = globset::GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(serde::de::Error::custom)?;
+    Ok(glob.compile_matcher())
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("error reading: {source}")]
@@ -36,4 +105,81 @@ impl Config {
         let config: Config = ron::de::from_reader(file)?;
         Ok(config)
     }
+
+    /// Start configuring mpv the way this config says, without any per-file settings.
+    pub fn mpv_builder(&self) -> mpv_remote::ConfigBuilder {
+        let mut builder = mpv_remote::MPV::builder();
+        builder
+            .fullscreen(self.fullscreen)
+            .options(self.mpv_options.clone())
+            .wrapper(self.mpv_wrapper.iter().map(OsString::from).collect());
+        if let Some(executable) = &self.mpv_executable {
+            builder.executable(executable);
+        }
+        for (name, value) in &self.mpv_env {
+            builder.env_var(name, value);
+        }
+        if let Some(dir) = &self.mpv_working_directory {
+            builder.current_dir(dir);
+        }
+        if let Some(socket) = &self.mpv_socket {
+            builder.ipc_server(socket);
+        }
+        builder
+    }
+
+    /// Resolve the mpv options for a file, given as a path relative to `self.path`.
+    pub fn mpv_options_for(&self, filename: &str) -> BTreeMap<String, String> {
+        let mut options = self.mpv_options.clone();
+        for profile in &self.profiles {
+            if profile.glob.is_match(filename) {
+                options.extend(profile.options.clone());
+            }
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_parses() {
+        let config: Config = ron::de::from_str(include_str!("../../example-config.ron")).unwrap();
+        // Left to `PATH`, and run directly.
+        assert_eq!(config.mpv_executable, None);
+        assert!(config.mpv_wrapper.is_empty());
+        assert!(config.mpv_env.is_empty());
+        assert!(config.mpv_builder().build().is_ok());
+    }
+
+    #[test]
+    fn profiles_override_in_order() {
+        let config: Config = ron::de::from_str(
+            r#"ChoosyConfig(
+                path: "/media",
+                mpv_options: {"volume": "50", "speed": "1.0"},
+                profiles: [
+                    (glob: "Anime/**", options: {"alang": "jpn", "slang": "eng"}),
+                    (glob: "Anime/Dubbed/*", options: {"alang": "eng"}),
+                    (glob: "Kids/*", options: {"volume": "30"}),
+                ],
+            )"#,
+        )
+        .unwrap();
+
+        let options = config.mpv_options_for("Anime/Dubbed/foo.mkv");
+        assert_eq!(options.get("alang").map(String::as_str), Some("eng"));
+        assert_eq!(options.get("slang").map(String::as_str), Some("eng"));
+        assert_eq!(options.get("volume").map(String::as_str), Some("50"));
+
+        let options = config.mpv_options_for("Kids/bar.mkv");
+        assert_eq!(options.get("volume").map(String::as_str), Some("30"));
+        assert_eq!(options.get("alang"), None);
+
+        // `*` must not cross directories.
+        let options = config.mpv_options_for("Kids/Old/baz.mkv");
+        assert_eq!(options.get("volume").map(String::as_str), Some("50"));
+    }
 }
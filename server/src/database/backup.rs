@@ -0,0 +1,158 @@
+//! Export and import of the media database, as JSON Lines.
+//!
+//! The format is one object per line, `{"path": "...", "exists": true, "watched": false}`.
+//! It is kept stable independent of how sled and sleigh store things; new fields get defaults, so old exports still import.
+
+use super::media::Media;
+use super::MediaDb;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use thiserror::Error;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct Record {
+    path: String,
+    #[serde(default)]
+    exists: bool,
+    #[serde(default)]
+    watched: bool,
+}
+
+impl Record {
+    pub(crate) fn new(path: String, media: Media) -> Self {
+        Record {
+            path,
+            exists: media.exists,
+            watched: media.watched,
+        }
+    }
+
+    fn into_media(self) -> (String, Media) {
+        let media = Media {
+            exists: self.exists,
+            watched: self.watched,
+        };
+        (self.path, media)
+    }
+}
+
+type EncodingError = <sleigh::encoding::Bincode as sleigh::encoding::Encoding>::Error;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("database error: {0}")]
+    DBRead(#[from] sleigh::GetError<EncodingError>),
+
+    #[error("database error: {0}")]
+    DBWrite(#[from] sleigh::InsertError<EncodingError>),
+
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("error encoding JSON: {0}")]
+    Encode(#[source] serde_json::Error),
+
+    #[error("line {line}: {source}")]
+    Parse {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Write every item of `media` to `out`. Returns how many were written.
+pub fn export(media: &MediaDb, mut out: impl Write) -> Result<u64, BackupError> {
+    let mut count = 0;
+    for result in media.iter() {
+        let (path, item) = result?;
+        serde_json::to_writer(&mut out, &Record::new(path, item)).map_err(BackupError::Encode)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Read items from `input` into `media`. Returns how many were read.
+///
+/// Items already in `media` are overwritten, and ones not in `input` are left alone.
+/// Nothing is written unless all of `input` parses.
+pub fn import(media: &MediaDb, input: impl BufRead) -> Result<u64, BackupError> {
+    let mut batch = sleigh::Batch::new();
+    let mut count = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line).map_err(|source| BackupError::Parse {
+            line: index + 1,
+            source,
+        })?;
+        let (path, item) = record.into_media();
+        batch
+            .insert(&path, &item)
+            .map_err(|error| BackupError::DBWrite(sleigh::InsertError::Serialize(error)))?;
+        count += 1;
+    }
+    media.apply_batch(batch)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media_db() -> MediaDb {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        MediaDb::new(db.open_tree("media").unwrap())
+    }
+
+    #[test]
+    fn roundtrip() {
+        let from = media_db();
+        from.insert(
+            &"a.mkv".to_string(),
+            &Media {
+                exists: true,
+                watched: true,
+            },
+        )
+        .unwrap();
+        from.insert(&"gone/b.mkv".to_string(), &Media::default())
+            .unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(export(&from, &mut buf).unwrap(), 2);
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "{\"path\":\"a.mkv\",\"exists\":true,\"watched\":true}\n\
+             {\"path\":\"gone/b.mkv\",\"exists\":false,\"watched\":false}\n"
+        );
+
+        let to = media_db();
+        assert_eq!(import(&to, &buf[..]).unwrap(), 2);
+        let mut exported = Vec::new();
+        export(&to, &mut exported).unwrap();
+        assert_eq!(exported, buf);
+    }
+
+    #[test]
+    fn import_old_export() {
+        let media = media_db();
+        import(&media, "{\"path\":\"a.mkv\",\"exists\":true}\n".as_bytes()).unwrap();
+        let item = media.get(&"a.mkv".to_string()).unwrap().unwrap();
+        assert!(item.exists);
+        assert!(!item.watched);
+    }
+
+    #[test]
+    fn import_bad_line() {
+        let media = media_db();
+        let input = "{\"path\":\"a.mkv\",\"exists\":true}\n\nnot json\n";
+        match import(&media, input.as_bytes()) {
+            Err(BackupError::Parse { line: 3, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(media.first().unwrap().is_none());
+    }
+}
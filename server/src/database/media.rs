@@ -1,46 +1,31 @@
-pub type MediaDb = sleigh::Tree<Media, Vec<Op>, sleigh::encoding::Bincode>;
+/// Media files, by path relative to `Config::path`.
+pub type MediaDb = sleigh::Tree<String, Media, Vec<Op>, sleigh::encoding::Bincode>;
+pub type AsyncMediaDb = sleigh::AsyncTree<String, Media, Vec<Op>, sleigh::encoding::Bincode>;
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-enum MediaVersioned {
-    // Never remove variants from this enum, or the tag on the wire goes out of sync.
-    // Never edit existing versions directly.
-    // Instead:
-    //
-    // - edit `Media` fields
-    // - add new `V(last+1)` variant, copy-paste `Media` fields into it
-    // - edit `Media::serialize` changing `V(prev)` to the new `V(new)`
-    // - add match arms and fix destructurings and struct instantiation until code compiles
-    V1 { exists: bool },
-}
-
-#[derive(serde::Deserialize, Debug, Default)]
-#[serde(from = "MediaVersioned")]
-pub struct Media {
-    // DO NOT EDIT directly, see MediaVersioned.
-    /// Exists on disk to the best of our knowledge.
-    pub exists: bool,
+// See `sleigh::versioned` for how to add fields.
+// Old versions are upgraded as they are read; `choosy db migrate` rewrites them all.
+sleigh::versioned! {
+    #[derive(Debug, Default)]
+    pub struct Media: MediaVersioned::V2 {
+        /// Exists on disk to the best of our knowledge.
+        pub exists: bool,
+        pub watched: bool,
+    }
+    upgrade from {
+        V1(MediaV1),
+    }
 }
 
-impl serde::Serialize for Media {
-    // Prefer this way over `#[serde(into="MediaVersioned")])` because this way we don't need to implement `Clone` for `Media`.
-    // This could probably be cleaned up with `serde(with)` for containers, if and when that is implemented: https://github.com/serde-rs/serde/issues/1118
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        // Change this to `V(latest)` when changing `Media`.
-        let ver = MediaVersioned::V1 {
-            exists: self.exists,
-        };
-        ver.serialize(serializer)
-    }
+#[derive(serde::Deserialize)]
+pub struct MediaV1 {
+    exists: bool,
 }
 
-impl From<MediaVersioned> for Media {
-    fn from(ver: MediaVersioned) -> Self {
-        match ver {
-            MediaVersioned::V1 { exists } => Media { exists },
-            // Add new `V(n)` variants here.
+impl From<MediaV1> for Media {
+    fn from(old: MediaV1) -> Self {
+        Media {
+            exists: old.exists,
+            watched: false,
         }
     }
 }
@@ -50,6 +35,7 @@ impl sleigh::Merge<Vec<Op>> for Media {
         for op in ops {
             match op {
                 Op::Exists(b) => self.exists = b,
+                Op::Watched(b) => self.watched = b,
             }
         }
         sleigh::MergeVerdict::Keep
@@ -60,4 +46,5 @@ impl sleigh::Merge<Vec<Op>> for Media {
 pub enum Op {
     // Never remove variants from this enum, or the tag on the wire goes out of sync.
     Exists(bool),
+    Watched(bool),
 }
@@ -1,2 +1,38 @@
+pub(crate) mod backup;
 pub(crate) mod media;
-pub use media::MediaDb;
+pub use media::{AsyncMediaDb, MediaDb};
+
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+/// Open the media tree, quarantining corrupt items.
+pub fn open_media(db: &sled::Db) -> Result<MediaDb, anyhow::Error> {
+    use anyhow::Context;
+
+    let tree = db
+        .open_tree("media")
+        .context("error opening database table for media")?;
+    let quarantine = db
+        .open_tree("media-quarantine")
+        .context("error opening database table for corrupt media")?;
+    if !quarantine.is_empty() {
+        warn!(
+            message = "database has quarantined corrupt items",
+            count = quarantine.len()
+        );
+    }
+    let media = MediaDb::with_corruption_policy(
+        tree,
+        sleigh::CorruptionPolicy::Quarantine(quarantine),
+        |corruption| {
+            let key = String::from_utf8_lossy(corruption.key);
+            error!(
+                message = "database has corrupt item, moved it to quarantine",
+                %key,
+                kind = ?corruption.kind,
+                error = %corruption.error,
+            );
+        },
+    );
+    Ok(media)
+}
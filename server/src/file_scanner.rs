@@ -1,10 +1,19 @@
+use crate::database::{self, MediaDb};
+use std::collections::BTreeSet;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, log, trace, warn};
 use walkdir::{DirEntry, WalkDir};
 
+/// How long to wait between scans; TODO inotify.
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(9);
+
+// New files are written in batches of this many, so the first scan of a big library is not one huge transaction.
+const NEW_FILES_PER_BATCH: usize = 2000;
+
 fn is_interesting(entry: &DirEntry) -> bool {
     let ext = match entry.path().extension() {
         None => return false,
@@ -92,3 +101,72 @@ pub fn scan(path: &Path) -> impl Iterator<Item = String> {
             }
         })
 }
+
+/// Scan `path` and bring `media` up to date with what was found.
+/// Errors are logged, and skipped.
+pub fn scan_once(path: &Path, media: &MediaDb) {
+    let found = scan(path);
+    let files: BTreeSet<String> = found.collect();
+    // debug!("files", { files: log::kv::Value::capture_debug(&files) });
+    let db = media.iter();
+    // New files are written a batch at a time, which is much faster on the first scan.
+    let mut new_files = sleigh::Batch::new();
+    let mut new_files_count = 0;
+    let apply = |new_files| {
+        if let Err(error) = media.apply_batch(new_files) {
+            warn!(message = "file scanner: database error", ?error)
+        }
+    };
+    let merge = itertools::merge_join_by(db, files, |result, file_path| match result {
+        Ok((key, _item)) => key.cmp(file_path),
+        Err(_) => std::cmp::Ordering::Less,
+    });
+    for merged in merge {
+        // debug!("merge", { merged: log::kv::Value::capture_debug(&merged) });
+        use itertools::EitherOrBoth::*;
+        match merged {
+            Left(Err(error)) => warn!(message = "file scanner: database error", ?error),
+            Left(Ok((key, item))) => {
+                // Found in database, not on filesystem.
+                if item.exists {
+                    let result = media.merge(&key, &vec![database::media::Op::Exists(false)]);
+                    match result {
+                        Ok(_) => (),
+                        Err(error) => {
+                            warn!(message = "file scanner: database error", ?error)
+                        }
+                    }
+                }
+            }
+            Right(file_path) => {
+                // Found on filesystem, not in database.
+                // Merged, in case something else wrote it since the scan started.
+                if let Err(error) =
+                    new_files.merge(&file_path, &vec![database::media::Op::Exists(true)])
+                {
+                    warn!(message = "file scanner: database error", ?error)
+                }
+                new_files_count += 1;
+                if new_files_count % NEW_FILES_PER_BATCH == 0 {
+                    apply(std::mem::take(&mut new_files));
+                }
+            }
+            Both(Err(error), _) => {
+                warn!(message = "file scanner: database error", ?error)
+            }
+            Both(Ok((key, item)), _file_path) => {
+                // Found in both; ensure database says exists=true.
+                if !item.exists {
+                    let result = media.merge(&key, &vec![database::media::Op::Exists(true)]);
+                    match result {
+                        Ok(_) => (),
+                        Err(error) => {
+                            warn!(message = "file scanner: database error", ?error)
+                        }
+                    }
+                }
+            }
+        }
+    }
+    apply(new_files);
+}
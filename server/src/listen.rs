@@ -0,0 +1,227 @@
+//! Where to listen for HTTP: a TCP address, a Unix socket, or whatever systemd passed in `LISTEN_FDS`.
+
+use anyhow::Context;
+use listenfd::ListenFd;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use thiserror::Error;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+/// Argument to `--listen`.
+#[derive(Debug, PartialEq)]
+pub enum Listen {
+    /// E.g. `127.0.0.1:8000` or `[::1]:8000`.
+    Tcp(SocketAddr),
+    /// A path with a slash, e.g. `/run/choosy/http.socket` or `./http.socket`, or anything after `unix:`.
+    Unix(PathBuf),
+}
+
+#[derive(Error, Debug)]
+#[error("not an IP address and port, nor a Unix socket path with a `/` or `unix:` in front: {0}")]
+pub struct ParseListenError(String);
+
+impl std::str::FromStr for Listen {
+    type Err = ParseListenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        if s.contains('/') {
+            return Ok(Listen::Unix(PathBuf::from(s)));
+        }
+        // Host names are not resolved, e.g. `localhost:8000`.
+        s.parse()
+            .map(Listen::Tcp)
+            .map_err(|_| ParseListenError(s.to_string()))
+    }
+}
+
+/// Parse permission bits given in octal, e.g. `660`.
+pub fn parse_mode(s: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s, 8)
+}
+
+// Bind in a private directory next to `path`, and move the socket into place once it has its permissions.
+// Binding at `path` directly would leave the socket open to anyone the umask allows, until the chmod.
+fn bind_with_mode(
+    path: &Path,
+    mode: u32,
+) -> Result<std::os::unix::net::UnixListener, anyhow::Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = tempfile::Builder::new()
+        .prefix(".choosy-socket")
+        .tempdir_in(parent)
+        .with_context(|| format!("cannot create directory in {:?}", parent))?;
+    let temporary = private.path().join("socket");
+    let listener = std::os::unix::net::UnixListener::bind(&temporary)
+        .with_context(|| format!("cannot listen on {:?}", temporary))?;
+    std::fs::set_permissions(&temporary, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("cannot set permissions of {:?}", temporary))?;
+    std::fs::rename(&temporary, path)
+        .with_context(|| format!("cannot move socket to {:?}", path))?;
+    Ok(listener)
+}
+
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    /// Listen where `listen` says, or else on the socket from systemd.
+    /// `mode` sets the permissions of a Unix socket we create, and is an error for any other socket.
+    pub fn open(listen: Option<&Listen>, mode: Option<u32>) -> Result<Self, anyhow::Error> {
+        let listen = match listen {
+            Some(listen) => listen,
+            None if mode.is_some() => {
+                anyhow::bail!(
+                    "--socket-mode only applies to a Unix socket path given with --listen"
+                )
+            }
+            None => return Self::from_env(),
+        };
+        match listen {
+            Listen::Tcp(_) if mode.is_some() => {
+                anyhow::bail!("--socket-mode only applies to a Unix socket path, not a TCP address")
+            }
+            Listen::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("cannot listen on {}", addr))?;
+                info!(message = "listening", %addr);
+                Ok(Listener::Tcp(listener))
+            }
+            Listen::Unix(path) => {
+                // A socket left behind by an earlier run would make bind fail.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)
+                            .with_context(|| format!("cannot remove old socket {:?}", path))?;
+                    }
+                }
+                let listener = match mode {
+                    None => std::os::unix::net::UnixListener::bind(path)
+                        .with_context(|| format!("cannot listen on {:?}", path))?,
+                    Some(mode) => bind_with_mode(path, mode)?,
+                };
+                info!(message = "listening", ?path);
+                Ok(Listener::Unix(listener))
+            }
+        }
+    }
+
+    fn from_env() -> Result<Self, anyhow::Error> {
+        let mut fds = ListenFd::from_env();
+        if fds.len() == 0 {
+            anyhow::bail!("need --listen, or a socket in LISTEN_FDS");
+        }
+        // On the wrong kind of socket, this leaves it in place for the next try.
+        if let Ok(Some(listener)) = fds.take_tcp_listener(0) {
+            return Ok(Listener::Tcp(listener));
+        }
+        let listener = fds
+            .take_unix_listener(0)
+            .context("LISTEN_FDS must set up listening sockets")?
+            .ok_or_else(|| anyhow::anyhow!("LISTEN_FDS must have set up a TCP or Unix socket"))?;
+        Ok(Listener::Unix(listener))
+    }
+
+    pub async fn serve(self, app: axum::Router) -> Result<(), anyhow::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                axum::Server::from_tcp(listener)?
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                axum::Server::builder(UnixAccept(listener))
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// RUST-WART hyper only knows how to accept TCP connections by itself.
+struct UnixAccept(tokio::net::UnixListener);
+
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _addr)| stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen() {
+        assert_eq!(
+            "127.0.0.1:8000".parse::<Listen>().unwrap(),
+            Listen::Tcp("127.0.0.1:8000".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:8000".parse::<Listen>().unwrap(),
+            Listen::Tcp("[::1]:8000".parse().unwrap())
+        );
+        assert_eq!(
+            "/run/choosy.socket".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("/run/choosy.socket"))
+        );
+        assert_eq!(
+            "./http.socket".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("./http.socket"))
+        );
+        assert_eq!(
+            "unix:http.socket".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("http.socket"))
+        );
+        for bad in ["localhost:8000", "0.0.0.0:80000", "http.socket"] {
+            assert!(bad.parse::<Listen>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn unix_socket_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("http.socket");
+        let listen = Listen::Unix(path.clone());
+        for _ in 0..2 {
+            // The second time around, the old socket is still there.
+            let listener = Listener::open(Some(&listen), Some(parse_mode("640").unwrap())).unwrap();
+            assert!(matches!(listener, Listener::Unix(_)));
+        }
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        // Nothing left behind from binding.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn socket_mode_needs_unix_socket() {
+        let mode = Some(parse_mode("660").unwrap());
+        let tcp = Listen::Tcp("127.0.0.1:0".parse().unwrap());
+        assert!(Listener::open(Some(&tcp), mode).is_err());
+        // Before even looking for a socket from systemd.
+        assert!(Listener::open(None, mode).is_err());
+    }
+}
@@ -1,15 +1,11 @@
-use axum::extract::Query;
-use axum::http::header::HeaderName;
+use axum::http::header::{self, HeaderName};
 use axum::http::HeaderMap;
 use axum::http::HeaderValue;
-use axum::http::StatusCode;
 use axum::response::Html;
 use axum::Json;
 use choosy_protocol as proto;
-use listenfd::ListenFd;
-use mpv_remote::MPV;
+use mpv_remote::{IPCError, MPV};
 use serde::Deserialize;
-use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -18,9 +14,17 @@ use structopt::StructOpt;
 use tracing::{debug, error, info, log, trace, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod admin;
+mod api_error;
+mod auth;
 mod config;
 mod database;
 mod file_scanner;
+mod listen;
+mod path_safety;
+mod status;
+mod websocket;
+use api_error::{ApiError, ApiJson, ApiQuery};
 use config::Config;
 
 #[derive(Clone, PartialEq)]
@@ -28,31 +32,83 @@ struct File {}
 
 struct State {
     config: Config,
-    media: database::MediaDb,
+    auth: auth::Auth,
+    media: database::AsyncMediaDb,
     playing: tokio::sync::Mutex<Option<MPV>>,
+    // Changes to what is playing; see `status::watch`.
+    status: tokio::sync::broadcast::Sender<proto::StatusResponse>,
 }
 
-async fn wasm_bg() -> (HeaderMap, &'static [u8]) {
+fn static_file(content_type: &'static str, bytes: &'static [u8]) -> (HeaderMap, &'static [u8]) {
     let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("application/wasm"),
+        HeaderValue::from_static(content_type),
     );
-    (headers, choosy_embed::wasm())
+    (headers, bytes)
+}
+
+async fn wasm_bg() -> (HeaderMap, &'static [u8]) {
+    static_file("application/wasm", choosy_embed::wasm())
 }
 
 async fn wasm_js() -> (HeaderMap, &'static [u8]) {
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        HeaderName::from_static("content-type"),
-        HeaderValue::from_static("application/javascript"),
-    );
-    (headers, choosy_embed::wasm_js())
+    static_file("application/javascript", choosy_embed::wasm_js())
+}
+
+const MANIFEST: &[u8] = include_bytes!("../../frontend/static/manifest.webmanifest");
+
+async fn manifest() -> (HeaderMap, &'static [u8]) {
+    static_file("application/manifest+json", MANIFEST)
 }
 
-async fn index_html() -> Html<&'static [u8]> {
+// Served from the root, so it can control the whole site.
+async fn service_worker() -> (HeaderMap, &'static [u8]) {
+    static_file(
+        "application/javascript",
+        include_bytes!("../../frontend/static/sw.js"),
+    )
+}
+
+async fn icon() -> (HeaderMap, &'static [u8]) {
+    static_file(
+        "image/svg+xml",
+        include_bytes!("../../frontend/static/icon.svg"),
+    )
+}
+
+async fn index_html(request_headers: HeaderMap) -> (HeaderMap, Html<&'static [u8]>) {
     let bytes = include_bytes!("../../frontend/static/index.html");
-    Html(bytes)
+    let mut headers = HeaderMap::new();
+    if let Some(cookie) = auth::csrf_cookie(&request_headers) {
+        headers.insert(header::SET_COOKIE, cookie);
+    }
+    (headers, Html(bytes))
+}
+
+async fn handle_login(
+    state: Arc<State>,
+    ApiJson(input): ApiJson<proto::LoginRequest>,
+    request_headers: HeaderMap,
+) -> Result<(HeaderMap, ()), ApiError> {
+    let cookie = state.auth.login(&request_headers, &input.password)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::SET_COOKIE, cookie);
+    Ok((headers, ()))
+}
+
+async fn handle_logout(
+    state: Arc<State>,
+    request_headers: HeaderMap,
+) -> Result<(HeaderMap, ()), ApiError> {
+    let cookie = state.auth.logout(&request_headers)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::SET_COOKIE, cookie);
+    Ok((headers, ()))
+}
+
+async fn openapi() -> Json<serde_json::Value> {
+    Json(proto::openapi::document())
 }
 
 fn build_search_re(query: &str) -> regex::Regex {
@@ -77,58 +133,127 @@ struct SearchQuery {
 
 async fn handle_search(
     state: Arc<State>,
-    query: Query<SearchQuery>,
-) -> Result<Json<proto::SearchResponse>, StatusCode> {
-    let search_re = build_search_re(&query.q);
+    ApiQuery(query): ApiQuery<SearchQuery>,
+    headers: HeaderMap,
+) -> Result<Json<proto::SearchResponse>, ApiError> {
+    state.auth.check(&headers, auth::Access::Read)?;
+    let result = search(&state, &query.q).await?;
+    Ok(Json(result))
+}
 
-    let iter = state
-        .media
-        .scan_prefix("")
-        .filter_map(|result| match result {
-            Err(error) => Some(Err(error)),
-            Ok((key, item)) => {
-                if !item.exists {
-                    return None;
-                }
+async fn search(state: &State, query: &str) -> Result<proto::SearchResponse, ApiError> {
+    use futures::{StreamExt, TryStreamExt};
 
-                let filename = String::from_utf8_lossy(key.as_ref()).into_owned();
-                if !search_re.is_match(&filename) {
-                    return None;
-                }
+    let search_re = build_search_re(query);
 
-                let hit = proto::SearchResult { filename };
-                Some(Ok(hit))
-            }
+    let stream = state
+        .media
+        .iter()
+        .try_filter_map(|(filename, item)| {
+            let hit = if item.exists && search_re.is_match(&filename) {
+                Some(proto::SearchResult { filename })
+            } else {
+                None
+            };
+            futures::future::ready(Ok(hit))
         })
         .take(1000);
-    let items: Result<
-        Vec<proto::SearchResult>,
-        sleigh::GetError<<sleigh::encoding::Bincode as sleigh::encoding::Encoding>::Error>,
-    > = iter.collect();
-    let items = items.map_err(|error| {
+    let items: Vec<proto::SearchResult> = stream.try_collect().await.map_err(|error| {
         warn!(message = "database error", ?error);
-        StatusCode::INTERNAL_SERVER_ERROR
+        ApiError::new(proto::ErrorCode::DatabaseError, error.to_string())
     })?;
-    let result = proto::SearchResponse { items };
+    Ok(proto::SearchResponse { items })
+}
+
+#[derive(Deserialize)]
+struct BrowseQuery {
+    #[serde(default)]
+    path: String,
+}
+
+async fn handle_browse(
+    state: Arc<State>,
+    ApiQuery(query): ApiQuery<BrowseQuery>,
+    headers: HeaderMap,
+) -> Result<Json<proto::BrowseResponse>, ApiError> {
+    state.auth.check(&headers, auth::Access::Read)?;
+    let result = browse(&state, &query.path).await?;
     Ok(Json(result))
 }
 
+// There are no directories in the database, only the files in them.
+async fn browse(state: &State, path: &str) -> Result<proto::BrowseResponse, ApiError> {
+    use futures::TryStreamExt;
+
+    let mut path = path.trim_start_matches('/').to_string();
+    if !path.is_empty() && !path.ends_with('/') {
+        path.push('/');
+    }
+    let mut directories: Vec<String> = Vec::new();
+    let mut files = Vec::new();
+    let mut stream = state.media.scan_prefix(path.as_str());
+    while let Some((filename, item)) = stream.try_next().await.map_err(|error| {
+        warn!(message = "database error", ?error);
+        ApiError::new(proto::ErrorCode::DatabaseError, error.to_string())
+    })? {
+        if !item.exists {
+            continue;
+        }
+        match filename[path.len()..].find('/') {
+            None => files.push(proto::SearchResult { filename }),
+            Some(slash) => {
+                let directory = &filename[..path.len() + slash + 1];
+                // Keys are sorted, so everything in a directory comes together.
+                if directories.last().map(String::as_str) != Some(directory) {
+                    directories.push(directory.to_string());
+                }
+            }
+        }
+    }
+    Ok(proto::BrowseResponse {
+        path,
+        directories,
+        files,
+    })
+}
+
 async fn handle_play(
     state: Arc<State>,
-    Json(input): Json<proto::PlayCommand>,
-) -> Result<(), StatusCode> {
-    let filename = input.filename;
+    ApiJson(input): ApiJson<proto::PlayCommand>,
+    headers: HeaderMap,
+) -> Result<(), ApiError> {
+    state.auth.check(&headers, auth::Access::Write)?;
+    play_file(state, input.filename).await
+}
+
+async fn play_file(state: Arc<State>, filename: String) -> Result<(), ApiError> {
     debug!(message = "play file", %filename);
-    // Confirm that the file is in our state.files
-    let exists = match state.media.get(&filename).expect("database error") {
-        Some(item) => item.exists,
-        None => false,
-    };
-    if !exists {
+    let path = resolve(&state, &filename)?;
+    play(state, &filename, path).await
+}
+
+fn resolve(state: &State, filename: &str) -> Result<path_safety::SafePath, ApiError> {
+    path_safety::resolve(
+        Path::new(&state.config.path),
+        filename,
+        state.config.follow_external_symlinks,
+    )
+    .map_err(|error| {
         // We might have removed the file concurrently, so this is not always an "attack".
-        warn!(message = "browser submitted invalid file", %filename);
-        return Ok(());
+        warn!(message = "browser submitted invalid file", %filename, %error);
+        not_found(filename, error)
+    })
+}
+
+async fn play(
+    state: Arc<State>,
+    filename: &str,
+    path: path_safety::SafePath,
+) -> Result<(), ApiError> {
+    if state.config.keep_player {
+        return play_in_kept_player(&state, filename, &path).await;
     }
+
     let mut events = {
         let mut playing_guard = state.playing.lock().await;
         if playing_guard.is_some() {
@@ -136,35 +261,28 @@ async fn handle_play(
             // TODO shut down old player and start new?
             // don't want to lose place, maybe always run with --save-position-on-quit
             //
-            // if not above, then inform frontend of error? then again, maybe i should just make "is playing" state visible to it, not as response to this.
-            return Ok(());
+            // maybe i should just make "is playing" state visible to the frontend, not only as response to this.
+            return Err(ApiError::new(
+                proto::ErrorCode::AlreadyPlaying,
+                "already playing",
+            ));
         }
-        let mut mpv_builder = MPV::builder();
-        mpv_builder.fullscreen(state.config.fullscreen);
-        let mpv_config = match mpv_builder.build() {
-            Ok(builder) => builder,
-            Err(error) => {
-                warn!(message = "error configuring MPV", ?error);
-                return Ok(());
-            }
-        };
-        // This is safe because we've confirmed the file is in our list of files, and that prevents hostile inputs.
-        //
-        // TODO Currently, validation checks that they are currently included in our known files, which is racy.
-        //
-        // RUST-WART No clean way to lexically prevent "/evil", "../evil" without reading symlinks and whatnot?
-        let mut path = PathBuf::new();
-        path.push(&state.config.path);
-        path.push(&filename);
-        let path = path.into_os_string();
-        let mpv = match mpv_config.play(&path) {
-            Ok(mpv) => mpv,
-            Err(error) => {
-                warn!(message = "cannot play media", %filename, ?error);
-                return Ok(());
-            }
-        };
+        let mut mpv_builder = state.config.mpv_builder();
+        mpv_builder.options(state.config.mpv_options_for(filename));
+        let mpv_config = mpv_builder.build().map_err(|error| {
+            warn!(message = "error configuring MPV", ?error);
+            player_failed("error configuring mpv", error)
+        })?;
+        let path = path.recheck().map_err(|error| {
+            warn!(message = "file changed before playing", %filename, %error);
+            not_found(filename, error)
+        })?;
+        let mpv = mpv_config.play(path.as_os_str()).map_err(|error| {
+            warn!(message = "cannot play media", %filename, ?error);
+            player_failed("cannot start mpv", error)
+        })?;
         let events = mpv.events().await;
+        status::watch(&state, &mpv).await;
         *playing_guard = Some(mpv);
         events
     };
@@ -178,7 +296,6 @@ async fn handle_play(
                     tokio::sync::broadcast::error::RecvError::Closed => break,
                     tokio::sync::broadcast::error::RecvError::Lagged(count) => {
                         debug!(message = "mpv events receiver lagged", count);
-                        break;
                     }
                 },
             }
@@ -204,6 +321,270 @@ async fn handle_play(
     Ok(())
 }
 
+fn not_found(filename: &str, error: path_safety::PathError) -> ApiError {
+    ApiError::new(
+        proto::ErrorCode::NotFound,
+        format!("{}: {}", filename, error),
+    )
+}
+
+fn player_failed(context: &str, error: impl std::fmt::Display) -> ApiError {
+    ApiError::new(
+        proto::ErrorCode::PlayerFailed,
+        format!("{}: {}", context, error),
+    )
+}
+
+/// Load `path` into the long-lived player, starting it first if needed.
+async fn play_in_kept_player(
+    state: &Arc<State>,
+    filename: &str,
+    path: &path_safety::SafePath,
+) -> Result<(), ApiError> {
+    let options = state.config.mpv_options_for(filename);
+    let mut playing_guard = state.playing.lock().await;
+    // Retry once, in case the player we had has died since the last time.
+    for _attempt in 0..2 {
+        let mpv = match playing_guard.take() {
+            Some(mpv) => mpv,
+            None => start_kept_player(state).await?,
+        };
+        let path = match path.recheck() {
+            Ok(path) => path,
+            Err(error) => {
+                warn!(message = "file changed before playing", %filename, %error);
+                *playing_guard = Some(mpv);
+                return Err(not_found(filename, error));
+            }
+        };
+        match mpv.loadfile(path.as_os_str(), &options).await {
+            Ok(_) => {
+                *playing_guard = Some(mpv);
+                return Ok(());
+            }
+            Err(IPCError::Disconnected) | Err(IPCError::Network(_)) => {
+                debug!("mpv went away, restarting it");
+                if let Err(error) = mpv.close().await {
+                    debug!(message = "old mpv exited with error", ?error);
+                }
+            }
+            Err(error) => {
+                warn!(message = "cannot play media", %filename, ?error);
+                *playing_guard = Some(mpv);
+                return Err(player_failed("cannot play media", error));
+            }
+        }
+    }
+    warn!(message = "mpv keeps going away", %filename);
+    Err(ApiError::new(
+        proto::ErrorCode::PlayerFailed,
+        "mpv keeps going away",
+    ))
+}
+
+// How many status changes a slow WebSocket may fall behind, before it misses some.
+const STATUS_BUFFER: usize = 16;
+
+// Wait this long before replacing a kept player that went away, in case it keeps crashing.
+const KEPT_PLAYER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Attach to the mpv left behind by an earlier run, or start a new idle one.
+///
+/// It is restarted if it crashes.
+/// If it exits cleanly, e.g. when the user quits it, it stays gone until the next play request.
+async fn start_kept_player(state: &Arc<State>) -> Result<MPV, ApiError> {
+    let config = &state.config;
+    let mpv = match &config.mpv_socket {
+        Some(socket) => match MPV::connect(socket).await {
+            Ok(mpv) => {
+                debug!(message = "attached to running mpv", %socket);
+                Some(mpv)
+            }
+            Err(error) => {
+                debug!(message = "no mpv to attach to", ?error);
+                None
+            }
+        },
+        None => None,
+    };
+    let mpv = match mpv {
+        Some(mpv) => mpv,
+        None => {
+            let mpv_config = config.mpv_builder().build().map_err(|error| {
+                warn!(message = "error configuring MPV", ?error);
+                player_failed("error configuring mpv", error)
+            })?;
+            mpv_config.start_idle().map_err(|error| {
+                warn!(message = "cannot start mpv", ?error);
+                player_failed("cannot start mpv", error)
+            })?
+        }
+    };
+    let events = mpv.events().await;
+    status::watch(state, &mpv).await;
+    tokio::spawn(restart_kept_player(Arc::clone(state), events));
+    Ok(mpv)
+}
+
+// Boxed, because it and `start_kept_player` start each other.
+fn restart_kept_player(
+    state: Arc<State>,
+    mut events: tokio::sync::broadcast::Receiver<mpv_remote::MPVEvent>,
+) -> futures::future::BoxFuture<'static, ()> {
+    Box::pin(async move {
+        // The events end when mpv disconnects.
+        while !matches!(
+            events.recv().await,
+            Err(tokio::sync::broadcast::error::RecvError::Closed)
+        ) {}
+        tokio::time::sleep(KEPT_PLAYER_RESTART_DELAY).await;
+
+        let mut playing_guard = state.playing.lock().await;
+        match &*playing_guard {
+            Some(mpv) if mpv.is_disconnected() => (),
+            // A play request already replaced it.
+            _ => return,
+        }
+        if let Some(mpv) = playing_guard.take() {
+            match mpv.close().await {
+                Ok(()) => {
+                    debug!("mpv exited, not restarting it");
+                    return;
+                }
+                Err(error) => warn!(message = "mpv went away, restarting it", ?error),
+            }
+        }
+        // On failure, the next play request tries again.
+        if let Ok(mpv) = start_kept_player(&state).await {
+            *playing_guard = Some(mpv);
+        }
+    })
+}
+
+async fn handle_queue(
+    state: Arc<State>,
+    ApiJson(input): ApiJson<proto::QueueCommand>,
+    headers: HeaderMap,
+) -> Result<(), ApiError> {
+    state.auth.check(&headers, auth::Access::Write)?;
+    queue_file(state, input.filename).await
+}
+
+async fn queue_file(state: Arc<State>, filename: String) -> Result<(), ApiError> {
+    debug!(message = "queue file", %filename);
+    let path = resolve(&state, &filename)?;
+    if let Some(mpv) = commander(&state).await {
+        let path = path
+            .recheck()
+            .map_err(|error| not_found(&filename, error))?;
+        let options = state.config.mpv_options_for(&filename);
+        mpv.append(path.as_os_str(), &options)
+            .await
+            .map_err(|error| {
+                warn!(message = "cannot queue media", %filename, ?error);
+                player_failed("cannot queue media", error)
+            })?;
+        return Ok(());
+    }
+    // Nothing to queue after.
+    play(state, &filename, path).await
+}
+
+// For commanding the player, without holding `state.playing` while mpv answers.
+async fn commander(state: &State) -> Option<mpv_remote::Commander> {
+    state.playing.lock().await.as_ref().map(MPV::commander)
+}
+
+fn not_playing() -> ApiError {
+    ApiError::new(proto::ErrorCode::NotPlaying, "nothing is playing")
+}
+
+async fn handle_pause(
+    state: Arc<State>,
+    ApiJson(input): ApiJson<proto::PauseCommand>,
+    headers: HeaderMap,
+) -> Result<(), ApiError> {
+    state.auth.check(&headers, auth::Access::Write)?;
+    pause(&state, input).await
+}
+
+async fn pause(state: &State, input: proto::PauseCommand) -> Result<(), ApiError> {
+    let mpv = commander(state).await.ok_or_else(not_playing)?;
+    let command = match input.pause {
+        Some(pause) => serde_json::json!(["set_property", "pause", pause]),
+        None => serde_json::json!(["cycle", "pause"]),
+    };
+    mpv.command(command)
+        .await
+        .map_err(|error| player_failed("cannot pause", error))?;
+    Ok(())
+}
+
+async fn handle_seek(
+    state: Arc<State>,
+    ApiJson(input): ApiJson<proto::SeekCommand>,
+    headers: HeaderMap,
+) -> Result<(), ApiError> {
+    state.auth.check(&headers, auth::Access::Write)?;
+    seek(&state, input).await
+}
+
+async fn seek(state: &State, input: proto::SeekCommand) -> Result<(), ApiError> {
+    let mpv = commander(state).await.ok_or_else(not_playing)?;
+    let mode = if input.relative {
+        "relative"
+    } else {
+        "absolute"
+    };
+    mpv.command(serde_json::json!(["seek", input.seconds, mode]))
+        .await
+        .map_err(|error| player_failed("cannot seek", error))?;
+    Ok(())
+}
+
+async fn handle_status(
+    state: Arc<State>,
+    headers: HeaderMap,
+) -> Result<Json<proto::StatusResponse>, ApiError> {
+    state.auth.check(&headers, auth::Access::Read)?;
+    let status = player_status(&state).await?;
+    Ok(Json(status))
+}
+
+async fn player_status(state: &State) -> Result<proto::StatusResponse, ApiError> {
+    let mpv = match commander(state).await {
+        None => return Ok(proto::StatusResponse { playing: None }),
+        Some(mpv) => mpv,
+    };
+    let property = |name: &'static str| mpv.command(serde_json::json!(["get_property", name]));
+    let path = match property("path").await {
+        Ok(serde_json::Value::String(path)) => path,
+        // An idle kept player has no file.
+        Ok(_) | Err(IPCError::FromMPV(_)) => return Ok(proto::StatusResponse { playing: None }),
+        Err(error) => return Err(player_failed("cannot get status", error)),
+    };
+    // Errors here are just missing information.
+    let paused = property("pause").await.ok().and_then(|v| v.as_bool());
+    let position = property("time-pos").await.ok().and_then(|v| v.as_f64());
+    let duration = property("duration").await.ok().and_then(|v| v.as_f64());
+    Ok(proto::StatusResponse {
+        playing: Some(proto::NowPlaying {
+            filename: relative_filename(&state.config, path),
+            paused: paused.unwrap_or(false),
+            position,
+            duration,
+        }),
+    })
+}
+
+// Relative to the media directory, like in the other responses, if it's in there.
+fn relative_filename(config: &Config, path: String) -> String {
+    match Path::new(&path).strip_prefix(&config.path) {
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => path,
+    }
+}
+
 #[derive(structopt::StructOpt, Debug)]
 #[structopt(
     name = "choosy",
@@ -211,13 +592,24 @@ async fn handle_play(
     no_version
 )]
 struct Opt {
-    /// Load configuration from file
+    /// Load configuration from file; required to serve
     #[structopt(long, parse(from_os_str))]
-    config: PathBuf,
+    config: Option<PathBuf>,
 
     /// Database location
     #[structopt(long, parse(from_os_str))]
     database: PathBuf,
+
+    /// Listen on a TCP address like `127.0.0.1:8000`, or a Unix socket path like `./http.socket` or `unix:http.socket`, instead of a socket from systemd
+    #[structopt(long)]
+    listen: Option<listen::Listen>,
+
+    /// Permissions of the Unix socket given with `--listen`, in octal
+    #[structopt(long, parse(try_from_str = listen::parse_mode))]
+    socket_mode: Option<u32>,
+
+    #[structopt(subcommand)]
+    command: Option<admin::Command>,
 }
 
 #[tokio::main]
@@ -228,87 +620,36 @@ async fn main() -> Result<(), anyhow::Error> {
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "choosy=debug,tower_http=debug".into()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        // Subcommands write their output to stdout.
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
     let opt = Opt::from_args();
-    let config = Config::load(opt.config).context("error loading config file")?;
-    let db = sled::open(opt.database).context("error opening database")?;
-    let tree = db
-        .open_tree("media")
-        .context("error opening database table for media")?;
-    let media = database::MediaDb::new(tree.clone());
+    if let Some(command) = opt.command {
+        return admin::run(command, opt.config.as_deref(), &opt.database);
+    }
+
+    let config_path = opt
+        .config
+        .ok_or_else(|| anyhow::anyhow!("--config is required to serve"))?;
+    let config = Config::load(config_path).context("error loading config file")?;
+    let db = sled::open(&opt.database).context("error opening database")?;
+    let media = database::open_media(&db)?;
     let state = Arc::new(State {
+        auth: auth::Auth::new(&config),
         config: config.clone(),
-        media,
+        media: database::AsyncMediaDb::new(media),
         playing: tokio::sync::Mutex::new(None),
+        status: tokio::sync::broadcast::channel(STATUS_BUFFER).0,
     });
 
     let _file_scanner = {
         let state = state.clone();
         std::thread::spawn(move || {
             loop {
-                let path = Path::new(&state.config.path);
-                let found = file_scanner::scan(path);
-                let files: BTreeSet<String> = found.collect();
-                // debug!("files", { files: log::kv::Value::capture_debug(&files) });
-                let db = state.media.scan_prefix("");
-                let merge = itertools::merge_join_by(db, files, |result, file_path| match result {
-                    Ok((key, _item)) => key.cmp(&sled::IVec::from(file_path.as_str())),
-                    Err(_) => std::cmp::Ordering::Less,
-                });
-                for merged in merge {
-                    // debug!("merge", { merged: log::kv::Value::capture_debug(&merged) });
-                    use itertools::EitherOrBoth::*;
-                    match merged {
-                        Left(Err(error)) => warn!(message = "file scanner: database error", ?error),
-                        Left(Ok((key, item))) => {
-                            // Found in database, not on filesystem.
-                            if item.exists {
-                                let result = state
-                                    .media
-                                    .merge(key, &vec![database::media::Op::Exists(false)]);
-                                match result {
-                                    Ok(_) => (),
-                                    Err(error) => {
-                                        warn!(message = "file scanner: database error", ?error)
-                                    }
-                                }
-                            }
-                        }
-                        Right(file_path) => {
-                            // Found on filesystem, not in database
-                            let result = state
-                                .media
-                                .merge(file_path, &vec![database::media::Op::Exists(true)]);
-                            match result {
-                                Ok(_) => (),
-                                Err(error) => {
-                                    warn!(message = "file scanner: database error", ?error)
-                                }
-                            }
-                        }
-                        Both(Err(error), _) => {
-                            warn!(message = "file scanner: database error", ?error)
-                        }
-                        Both(Ok((key, item)), _file_path) => {
-                            // Found in both; ensure database says exists=true.
-                            if !item.exists {
-                                let result = state
-                                    .media
-                                    .merge(key, &vec![database::media::Op::Exists(true)]);
-                                match result {
-                                    Ok(_) => (),
-                                    Err(error) => {
-                                        warn!(message = "file scanner: database error", ?error)
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                // TODO inotify
-                std::thread::sleep(Duration::from_secs(9));
+                // This is a thread of its own, so blocking is fine.
+                file_scanner::scan_once(Path::new(&state.config.path), state.media.blocking());
+                std::thread::sleep(file_scanner::RESCAN_INTERVAL);
             }
         })
     };
@@ -318,30 +659,594 @@ async fn main() -> Result<(), anyhow::Error> {
         .route("/choosy_frontend_bg.wasm", get(wasm_bg))
         .route("/choosy_frontend.js", get(wasm_js))
         .route("/", get(index_html))
+        // The frontend keeps its state after `#`, but these are also links into it.
+        .route("/search", get(index_html))
+        .route("/browse", get(index_html))
+        .route("/browse/*path", get(index_html))
+        .route("/file/*path", get(index_html))
+        .route("/manifest.webmanifest", get(manifest))
+        .route("/sw.js", get(service_worker))
+        .route("/icon.svg", get(icon))
         .route(
-            "/search",
+            &format!("{}/search", proto::API_PREFIX),
             get({
                 let state = Arc::clone(&state);
-                move |query| handle_search(state, query)
+                move |query, headers| handle_search(state, query, headers)
+            }),
+        )
+        .route(
+            &format!("{}/browse", proto::API_PREFIX),
+            get({
+                let state = Arc::clone(&state);
+                move |query, headers| handle_browse(state, query, headers)
+            }),
+        )
+        .route(
+            &format!("{}/play", proto::API_PREFIX),
+            post({
+                let state = Arc::clone(&state);
+                move |input, headers| handle_play(state, input, headers)
+            }),
+        )
+        .route(
+            &format!("{}/queue", proto::API_PREFIX),
+            post({
+                let state = Arc::clone(&state);
+                move |input, headers| handle_queue(state, input, headers)
+            }),
+        )
+        .route(
+            &format!("{}/pause", proto::API_PREFIX),
+            post({
+                let state = Arc::clone(&state);
+                move |input, headers| handle_pause(state, input, headers)
             }),
         )
         .route(
-            "/play",
+            &format!("{}/seek", proto::API_PREFIX),
             post({
                 let state = Arc::clone(&state);
-                move |input| handle_play(state, input)
+                move |input, headers| handle_seek(state, input, headers)
+            }),
+        )
+        .route(
+            &format!("{}/status", proto::API_PREFIX),
+            get({
+                let state = Arc::clone(&state);
+                move |headers| handle_status(state, headers)
             }),
         )
+        .route(
+            &format!("{}/ws", proto::API_PREFIX),
+            get({
+                let state = Arc::clone(&state);
+                move |ws, headers| websocket::handle_ws(state, ws, headers)
+            }),
+        )
+        .route(
+            &format!("{}/login", proto::API_PREFIX),
+            post({
+                let state = Arc::clone(&state);
+                move |input, headers| handle_login(state, input, headers)
+            }),
+        )
+        .route(
+            &format!("{}/logout", proto::API_PREFIX),
+            post({
+                let state = Arc::clone(&state);
+                move |headers| handle_logout(state, headers)
+            }),
+        )
+        .route(&format!("{}/openapi.json", proto::API_PREFIX), get(openapi))
         .layer(tower_http::trace::TraceLayer::new_for_http());
 
-    let mut fds = ListenFd::from_env();
-    let listener = fds
-        .take_tcp_listener(0)
-        .context("LISTEN_FDS must set up listening sockets")?
-        .ok_or_else(|| anyhow::anyhow!("LISTEN_FDS must have set up a TCP socket"))?;
+    let listener = listen::Listener::open(opt.listen.as_ref(), opt.socket_mode)?;
+    listener.serve(app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
 
-    axum::Server::from_tcp(listener)?
-        .serve(app.into_make_service())
-        .await?;
-    Ok(())
+    // `extra` is more RON fields for the config.
+    fn test_state(dir: &Path, script: serde_json::Value, extra: &str) -> Arc<State> {
+        let config = format!(
+            r#"ChoosyConfig(
+                path: {:?},
+                fullscreen: false,
+                mpv_executable: Some({:?}),
+                mpv_env: {{
+                    "FAKE_MPV_SCRIPT": {:?},
+                    "FAKE_MPV_LOG": {:?},
+                }},
+                {}
+            )"#,
+            dir,
+            fake_mpv::path(),
+            script.to_string(),
+            dir.join("fake-mpv.log"),
+            extra,
+        );
+        let config: Config = ron::de::from_str(&config).expect("test config must parse");
+        for filename in ["known.mkv", "other.mkv"] {
+            std::fs::write(dir.join(filename), b"").unwrap();
+        }
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("must open temporary database");
+        let media = database::MediaDb::new(db.open_tree("media").unwrap());
+        media
+            .merge(
+                &"known.mkv".to_string(),
+                &vec![database::media::Op::Exists(true)],
+            )
+            .unwrap();
+        Arc::new(State {
+            auth: auth::Auth::new(&config),
+            config,
+            media: database::AsyncMediaDb::new(media),
+            playing: tokio::sync::Mutex::new(None),
+            status: tokio::sync::broadcast::channel(STATUS_BUFFER).0,
+        })
+    }
+
+    // What the frontend sends from a browser that loaded the page.
+    fn browser() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("localhost:8000"));
+        headers.insert(header::COOKIE, HeaderValue::from_static("choosy_csrf=t0k"));
+        headers.insert(auth::CSRF_HEADER, HeaderValue::from_static("t0k"));
+        headers
+    }
+
+    fn play(filename: &str) -> ApiJson<proto::PlayCommand> {
+        ApiJson(proto::PlayCommand {
+            filename: filename.to_string(),
+        })
+    }
+
+    async fn playing_path(state: &State) -> Option<serde_json::Value> {
+        let guard = state.playing.lock().await;
+        match &*guard {
+            None => None,
+            Some(mpv) => Some(
+                mpv.command(serde_json::json!(["get_property", "path"]))
+                    .await
+                    .unwrap(),
+            ),
+        }
+    }
+
+    async fn wait_until_stopped(state: &State) {
+        for _ in 0..100 {
+            if state.playing.lock().await.is_none() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("player did not stop");
+    }
+
+    #[tokio::test]
+    async fn play_unknown_file_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        let error = handle_play(state.clone(), play("unknown.mkv"), browser())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, proto::ErrorCode::NotFound);
+        assert!(state.playing.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn play_outside_media_directory_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("media");
+        std::fs::create_dir(&media).unwrap();
+        std::fs::write(dir.path().join("known.mkv"), b"").unwrap();
+        let state = test_state(&media, serde_json::json!([]), "");
+        let error = handle_play(state.clone(), play("../known.mkv"), browser())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, proto::ErrorCode::NotFound);
+        assert!(state.playing.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn play_without_csrf_token_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        let mut headers = browser();
+        headers.remove(auth::CSRF_HEADER);
+        let error = handle_play(state.clone(), play("known.mkv"), headers)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert!(state.playing.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn play_from_script_without_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        // No cookies, no token: what `choosy-cli` sends.
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("localhost:8000"));
+        handle_play(state.clone(), play("known.mkv"), headers)
+            .await
+            .unwrap();
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        mpv.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn play_while_playing_keeps_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        let error = handle_play(state.clone(), play("other.mkv"), browser())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, proto::ErrorCode::AlreadyPlaying);
+        let expected = dir.path().join("known.mkv");
+        assert_eq!(
+            playing_path(&state).await,
+            Some(serde_json::json!(expected.to_str().unwrap()))
+        );
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        mpv.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn player_exit_clears_playing() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(
+            dir.path(),
+            serde_json::json!([{"sleep": 0.1}, {"exit": 0}]),
+            "",
+        );
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        wait_until_stopped(&state).await;
+
+        // Now that the first player is gone, we can start another.
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        wait_until_stopped(&state).await;
+    }
+
+    // Parsed lines of the fake mpv log, for all processes started so far.
+    fn fake_mpv_log(dir: &Path) -> Vec<serde_json::Value> {
+        let log = std::fs::read_to_string(dir.join("fake-mpv.log")).unwrap_or_default();
+        log.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn fake_mpv_starts(dir: &Path) -> usize {
+        fake_mpv_log(dir)
+            .iter()
+            .filter(|line| line.get("args").is_some())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn kept_player_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "keep_player: true,");
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        handle_play(state.clone(), play("other.mkv"), browser())
+            .await
+            .unwrap();
+        let expected = dir.path().join("other.mkv");
+        assert_eq!(
+            playing_path(&state).await,
+            Some(serde_json::json!(expected.to_str().unwrap()))
+        );
+        assert_eq!(fake_mpv_starts(dir.path()), 1);
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        mpv.close().await.unwrap();
+    }
+
+    async fn status(state: &Arc<State>) -> Option<proto::NowPlaying> {
+        handle_status(state.clone(), browser())
+            .await
+            .unwrap()
+            .0
+            .playing
+    }
+
+    #[tokio::test]
+    async fn control_kept_player() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "keep_player: true,");
+        assert_eq!(status(&state).await, None);
+        let error = handle_pause(
+            state.clone(),
+            ApiJson(proto::PauseCommand { pause: None }),
+            browser(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code, proto::ErrorCode::NotPlaying);
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        assert_eq!(
+            status(&state).await,
+            Some(proto::NowPlaying {
+                filename: "known.mkv".to_string(),
+                paused: false,
+                position: Some(0.0),
+                duration: Some(3600.0),
+            })
+        );
+
+        handle_pause(
+            state.clone(),
+            ApiJson(proto::PauseCommand { pause: None }),
+            browser(),
+        )
+        .await
+        .unwrap();
+        for (seconds, relative) in [(30.0, false), (-10.0, true)] {
+            handle_seek(
+                state.clone(),
+                ApiJson(proto::SeekCommand { seconds, relative }),
+                browser(),
+            )
+            .await
+            .unwrap();
+        }
+        let now = status(&state).await.unwrap();
+        assert!(now.paused);
+        assert_eq!(now.position, Some(20.0));
+
+        handle_queue(
+            state.clone(),
+            ApiJson(proto::QueueCommand {
+                filename: "other.mkv".to_string(),
+            }),
+            browser(),
+        )
+        .await
+        .unwrap();
+        let flags: Vec<serde_json::Value> = fake_mpv_log(dir.path())
+            .iter()
+            .filter(|line| line["command"]["name"] == "loadfile")
+            .map(|line| line["command"]["flags"].clone())
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                serde_json::json!("replace"),
+                serde_json::json!("append-play")
+            ]
+        );
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        mpv.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kept_player_is_restarted_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(
+            dir.path(),
+            serde_json::json!([{"expect": "loadfile"}, {"exit": 1}]),
+            "keep_player: true,",
+        );
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        // Wait for the crash.
+        loop {
+            let guard = state.playing.lock().await;
+            let mpv = guard.as_ref().expect("kept player must stay set");
+            if mpv
+                .command(serde_json::json!(["client_name"]))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            drop(guard);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        assert_eq!(fake_mpv_starts(dir.path()), 2);
+        let loads = fake_mpv_log(dir.path())
+            .iter()
+            .filter(|line| line["command"]["name"] == "loadfile")
+            .count();
+        assert_eq!(loads, 2);
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        // The second one crashed too, by now or soon.
+        let _ignore_error = mpv.close().await;
+    }
+
+    #[tokio::test]
+    async fn kept_player_is_restarted_without_play() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(
+            dir.path(),
+            serde_json::json!([{"expect": "loadfile"}, {"exit": 1}]),
+            "keep_player: true,",
+        );
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        let restarted = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let guard = state.playing.lock().await;
+                if fake_mpv_starts(dir.path()) == 2 && guard.is_some() {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), restarted)
+            .await
+            .expect("timeout waiting for mpv restart");
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        assert!(!mpv.is_disconnected());
+        mpv.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kept_player_is_not_restarted_after_quit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(
+            dir.path(),
+            serde_json::json!([{"expect": "loadfile"}, {"exit": 0}]),
+            "keep_player: true,",
+        );
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        wait_until_stopped(&state).await;
+        // Give a restart every chance to happen.
+        tokio::time::sleep(KEPT_PLAYER_RESTART_DELAY * 2).await;
+        assert!(state.playing.lock().await.is_none());
+        assert_eq!(fake_mpv_starts(dir.path()), 1);
+    }
+
+    async fn wait_for_status(
+        status: &mut tokio::sync::broadcast::Receiver<proto::StatusResponse>,
+        expected: proto::StatusResponse,
+    ) {
+        let matched =
+            async { while status.recv().await.expect("status must not close") != expected {} };
+        tokio::time::timeout(Duration::from_secs(10), matched)
+            .await
+            .expect("timeout waiting for status");
+    }
+
+    #[tokio::test]
+    async fn status_is_pushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        let mut status = state.status.subscribe();
+        let now_playing = |paused| proto::StatusResponse {
+            playing: Some(proto::NowPlaying {
+                filename: "known.mkv".to_string(),
+                paused,
+                position: Some(0.0),
+                duration: Some(3600.0),
+            }),
+        };
+
+        handle_play(state.clone(), play("known.mkv"), browser())
+            .await
+            .unwrap();
+        wait_for_status(&mut status, now_playing(false)).await;
+        pause(&state, proto::PauseCommand { pause: Some(true) })
+            .await
+            .unwrap();
+        wait_for_status(&mut status, now_playing(true)).await;
+
+        let mpv = state.playing.lock().await.take().unwrap();
+        mpv.close().await.unwrap();
+        wait_for_status(&mut status, proto::StatusResponse { playing: None }).await;
+    }
+
+    #[tokio::test]
+    async fn websocket_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        let respond = |request| websocket::respond(state.clone(), request);
+        assert_eq!(
+            respond(proto::Request::Search {
+                q: "KNOWN".to_string()
+            })
+            .await,
+            proto::Response::Search(proto::SearchResponse {
+                items: vec![proto::SearchResult {
+                    filename: "known.mkv".to_string()
+                }],
+            })
+        );
+        let error_code = |response| match response {
+            proto::Response::Error(error) => error.code,
+            other => panic!("expected an error: {:?}", other),
+        };
+        assert_eq!(
+            error_code(
+                respond(proto::Request::Play(proto::PlayCommand {
+                    filename: "unknown.mkv".to_string(),
+                }))
+                .await
+            ),
+            proto::ErrorCode::NotFound
+        );
+        assert_eq!(
+            error_code(respond(proto::Request::Pause(proto::PauseCommand { pause: None })).await),
+            proto::ErrorCode::NotPlaying
+        );
+        assert_eq!(
+            respond(proto::Request::Status).await,
+            proto::Response::Status(proto::StatusResponse { playing: None })
+        );
+    }
+
+    #[test]
+    fn manifest_is_json() {
+        let manifest: serde_json::Value = serde_json::from_slice(MANIFEST).unwrap();
+        assert_eq!(manifest["start_url"], "/");
+        assert_eq!(manifest["icons"][0]["src"], "/icon.svg");
+    }
+
+    #[tokio::test]
+    async fn browse_lists_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path(), serde_json::json!([]), "");
+        for filename in [
+            "Movies/a.mkv",
+            "Movies/Old/b.mkv",
+            "Movies/Old/c.mkv",
+            "Movies/Older/d.mkv",
+            "Moviesque.mkv",
+        ] {
+            state
+                .media
+                .merge(
+                    filename.to_string(),
+                    vec![database::media::Op::Exists(true)],
+                )
+                .await
+                .unwrap();
+        }
+        let top = browse(&state, "").await.unwrap();
+        assert_eq!(top.directories, ["Movies/"]);
+        let names = |files: Vec<proto::SearchResult>| -> Vec<String> {
+            files.into_iter().map(|file| file.filename).collect()
+        };
+        assert_eq!(names(top.files), ["Moviesque.mkv", "known.mkv"]);
+        let movies = browse(&state, "Movies").await.unwrap();
+        assert_eq!(movies.path, "Movies/");
+        assert_eq!(movies.directories, ["Movies/Old/", "Movies/Older/"]);
+        assert_eq!(names(movies.files), ["Movies/a.mkv"]);
+    }
 }
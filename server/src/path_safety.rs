@@ -0,0 +1,180 @@
+//! Turning a filename from the browser into a path under the media directory, without letting it point anywhere else.
+//!
+//! The filename is checked lexically first, and then resolved on disk, so symlinks cannot lead out either.
+//! Files can be renamed and symlinks changed between the check and starting mpv, so `SafePath::recheck` is meant to be called right before handing the path to mpv.
+
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("empty path")]
+    Empty,
+
+    #[error("absolute path")]
+    Absolute,
+
+    #[error("path contains `..`")]
+    ParentDir,
+
+    #[error("path leads outside the media directory")]
+    Escapes,
+
+    #[error("not a file")]
+    NotFile,
+
+    #[error("cannot resolve path: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// A file under the media directory, as checked by `resolve`.
+#[derive(Debug)]
+pub struct SafePath {
+    root: PathBuf,
+    path: PathBuf,
+    follow_external_symlinks: bool,
+}
+
+impl SafePath {
+    /// Check again that the path is still safe to use, and return it.
+    /// The path is not resolved, so mpv sees the same path as the user does.
+    pub fn recheck(&self) -> Result<&Path, PathError> {
+        check_on_disk(&self.root, &self.path, self.follow_external_symlinks)?;
+        Ok(&self.path)
+    }
+}
+
+/// Check that `filename` only names things below its directory, without looking at the disk.
+pub fn check_relative(filename: &str) -> Result<&Path, PathError> {
+    let relative = Path::new(filename);
+    if filename.is_empty() {
+        return Err(PathError::Empty);
+    }
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            Component::ParentDir => return Err(PathError::ParentDir),
+            Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute),
+        }
+    }
+    Ok(relative)
+}
+
+/// Resolve `filename` relative to `root`, making sure it is a file inside `root`.
+///
+/// Unless `follow_external_symlinks` is set, symlinks are only allowed to point to somewhere else inside `root`.
+pub fn resolve(
+    root: &Path,
+    filename: &str,
+    follow_external_symlinks: bool,
+) -> Result<SafePath, PathError> {
+    let relative = check_relative(filename)?;
+    let path = root.join(relative);
+    check_on_disk(root, &path, follow_external_symlinks)?;
+    Ok(SafePath {
+        root: root.to_path_buf(),
+        path,
+        follow_external_symlinks,
+    })
+}
+
+fn check_on_disk(
+    root: &Path,
+    path: &Path,
+    follow_external_symlinks: bool,
+) -> Result<(), PathError> {
+    let canonical = path.canonicalize()?;
+    if !follow_external_symlinks {
+        let root = root.canonicalize()?;
+        if !canonical.starts_with(&root) {
+            return Err(PathError::Escapes);
+        }
+    }
+    if !canonical.metadata()?.is_file() {
+        return Err(PathError::NotFile);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn hostile_filenames() {
+        assert!(matches!(check_relative(""), Err(PathError::Empty)));
+        assert!(matches!(
+            check_relative("/etc/passwd"),
+            Err(PathError::Absolute)
+        ));
+        assert!(matches!(
+            check_relative("../evil.mkv"),
+            Err(PathError::ParentDir)
+        ));
+        assert!(matches!(
+            check_relative("Movies/../../evil.mkv"),
+            Err(PathError::ParentDir)
+        ));
+        assert!(matches!(
+            check_relative("Movies/.."),
+            Err(PathError::ParentDir)
+        ));
+        assert_eq!(
+            check_relative("Movies/./good.mkv").unwrap(),
+            Path::new("Movies/./good.mkv")
+        );
+        assert_eq!(
+            check_relative("..good.mkv").unwrap(),
+            Path::new("..good.mkv")
+        );
+    }
+
+    #[test]
+    fn symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("media");
+        std::fs::create_dir_all(root.join("Movies")).unwrap();
+        std::fs::write(root.join("Movies/good.mkv"), b"").unwrap();
+        std::fs::write(dir.path().join("secret.mkv"), b"").unwrap();
+        symlink("Movies/good.mkv", root.join("inside.mkv")).unwrap();
+        symlink(dir.path().join("secret.mkv"), root.join("outside.mkv")).unwrap();
+        symlink(dir.path(), root.join("parent")).unwrap();
+
+        let safe = resolve(&root, "Movies/good.mkv", false).unwrap();
+        assert_eq!(safe.recheck().unwrap(), root.join("Movies/good.mkv"));
+        assert!(resolve(&root, "inside.mkv", false).is_ok());
+        assert!(matches!(
+            resolve(&root, "outside.mkv", false),
+            Err(PathError::Escapes)
+        ));
+        assert!(matches!(
+            resolve(&root, "parent/secret.mkv", false),
+            Err(PathError::Escapes)
+        ));
+        assert!(resolve(&root, "outside.mkv", true).is_ok());
+        assert!(matches!(
+            resolve(&root, "Movies", false),
+            Err(PathError::NotFile)
+        ));
+        assert!(matches!(
+            resolve(&root, "missing.mkv", false),
+            Err(PathError::IO(_))
+        ));
+    }
+
+    #[test]
+    fn recheck_after_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("media");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("good.mkv"), b"").unwrap();
+        std::fs::write(dir.path().join("secret.mkv"), b"").unwrap();
+
+        let safe = resolve(&root, "good.mkv", false).unwrap();
+        // Swapped for a symlink after the first check.
+        std::fs::remove_file(root.join("good.mkv")).unwrap();
+        symlink(dir.path().join("secret.mkv"), root.join("good.mkv")).unwrap();
+        assert!(matches!(safe.recheck(), Err(PathError::Escapes)));
+    }
+}
@@ -0,0 +1,134 @@
+//! Player status, pushed to `State::status` as mpv reports changes.
+//!
+//! One task per player follows observed properties, so WebSocket clients don't each have to ask mpv.
+
+use crate::State;
+use choosy_protocol as proto;
+use mpv_remote::{MPVEvent, MPVEventKind, PropertyChange, MPV};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+// Their index + 1 is the observer id; mpv tells the current values right away.
+const OBSERVED: &[&str] = &["path", "pause", "duration", "time-pos"];
+
+/// Push status changes of `mpv` to `state.status`, until it goes away.
+///
+/// Call before anyone else can load files into it, or the first changes may be missed.
+pub async fn watch(state: &Arc<State>, mpv: &MPV) {
+    let events = mpv.events().await;
+    for (index, name) in OBSERVED.iter().enumerate() {
+        let command = serde_json::json!(["observe_property", index + 1, name]);
+        if let Err(error) = mpv.command(command).await {
+            warn!(message = "cannot observe mpv property", %name, ?error);
+        }
+    }
+    tokio::spawn(push(Arc::clone(state), events));
+}
+
+#[derive(Default)]
+struct Playing {
+    path: Option<String>,
+    paused: bool,
+    position: Option<f64>,
+    duration: Option<f64>,
+}
+
+impl Playing {
+    fn update(&mut self, change: &PropertyChange) {
+        match change {
+            PropertyChange::Path { path } => {
+                // A new file; its position and duration come in later events.
+                self.path = path.clone();
+                self.position = None;
+                self.duration = None;
+            }
+            PropertyChange::Pause { paused } => self.paused = *paused,
+            PropertyChange::TimePos { seconds } => self.position = Some(seconds.as_secs_f64()),
+            PropertyChange::Duration { seconds } => {
+                self.duration = seconds.map(|duration| duration.as_secs_f64())
+            }
+        }
+    }
+
+    fn status(&self, state: &State) -> proto::StatusResponse {
+        proto::StatusResponse {
+            playing: self.path.as_ref().map(|path| proto::NowPlaying {
+                filename: crate::relative_filename(&state.config, path.clone()),
+                paused: self.paused,
+                position: self.position,
+                duration: self.duration,
+            }),
+        }
+    }
+}
+
+// The position changes with every frame; telling about whole seconds is enough.
+fn is_news(last: &proto::StatusResponse, status: &proto::StatusResponse) -> bool {
+    match (&last.playing, &status.playing) {
+        (Some(last), Some(now)) => {
+            let seconds = |position: Option<f64>| position.map(|seconds| seconds.floor());
+            last.filename != now.filename
+                || last.paused != now.paused
+                || last.duration != now.duration
+                || seconds(last.position) != seconds(now.position)
+        }
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+async fn push(state: Arc<State>, mut events: tokio::sync::broadcast::Receiver<MPVEvent>) {
+    let mut playing = Playing::default();
+    let mut last = proto::StatusResponse { playing: None };
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // Whatever we missed, later changes correct.
+            Err(RecvError::Lagged(count)) => {
+                debug!(message = "mpv status receiver lagged", count);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let change = match event.kind() {
+            MPVEventKind::PropertyChange(change) => change,
+            MPVEventKind::StartFile { .. } => continue,
+        };
+        playing.update(change);
+        let status = playing.status(&state);
+        if is_news(&last, &status) {
+            // Nobody may be listening.
+            let _ = state.status.send(status.clone());
+            last = status;
+        }
+    }
+    let _ = state.status.send(proto::StatusResponse { playing: None });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(position: f64) -> proto::StatusResponse {
+        proto::StatusResponse {
+            playing: Some(proto::NowPlaying {
+                filename: "a.mkv".to_string(),
+                paused: false,
+                position: Some(position),
+                duration: Some(60.0),
+            }),
+        }
+    }
+
+    #[test]
+    fn news() {
+        let idle = proto::StatusResponse { playing: None };
+        assert!(!is_news(&idle, &idle));
+        assert!(is_news(&idle, &playing(0.0)));
+        assert!(is_news(&playing(0.0), &idle));
+        assert!(!is_news(&playing(1.1), &playing(1.9)));
+        assert!(is_news(&playing(1.9), &playing(2.0)));
+    }
+}
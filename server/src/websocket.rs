@@ -0,0 +1,171 @@
+//! The `/ws` endpoint: requests and responses as in the rest of the API, plus player status pushed as it changes.
+//!
+//! Requests are handled concurrently, so responses can come back in a different order; clients tell them apart by `id`.
+//! A search or browse cancels the one before it, which then gets no response.
+
+use crate::api_error::ApiError;
+use crate::{auth, State};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use choosy_protocol as proto;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, log, trace, warn};
+
+pub async fn handle_ws(state: Arc<State>, ws: WebSocketUpgrade, headers: HeaderMap) -> Response {
+    if let Err(status) = state.auth.check(&headers, auth::Access::WebSocket) {
+        return ApiError::from(status).into_response();
+    }
+    ws.on_upgrade(move |socket| serve(state, socket))
+        .into_response()
+}
+
+pub async fn respond(state: Arc<State>, request: proto::Request) -> proto::Response {
+    let result = match request {
+        proto::Request::Search { q } => {
+            crate::search(&state, &q).await.map(proto::Response::Search)
+        }
+        proto::Request::Browse { path } => crate::browse(&state, &path)
+            .await
+            .map(proto::Response::Browse),
+        proto::Request::Play(command) => crate::play_file(state, command.filename)
+            .await
+            .map(|()| proto::Response::Done),
+        proto::Request::Queue(command) => crate::queue_file(state, command.filename)
+            .await
+            .map(|()| proto::Response::Done),
+        proto::Request::Pause(command) => crate::pause(&state, command)
+            .await
+            .map(|()| proto::Response::Done),
+        proto::Request::Seek(command) => crate::seek(&state, command)
+            .await
+            .map(|()| proto::Response::Done),
+        proto::Request::Status => crate::player_status(&state)
+            .await
+            .map(proto::Response::Status),
+    };
+    match result {
+        Ok(response) => response,
+        Err(error) => proto::Response::Error(error.into()),
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &proto::ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("JSON serialize of response must work");
+    socket.send(Message::Text(text)).await
+}
+
+// The id of a message that is not a valid `ClientMessage`, if it has one, to answer with the error.
+fn message_id(text: &str) -> Option<u64> {
+    #[derive(serde::Deserialize)]
+    struct Id {
+        id: u64,
+    }
+    serde_json::from_str::<Id>(text).ok().map(|Id { id }| id)
+}
+
+async fn serve(state: Arc<State>, mut socket: WebSocket) {
+    let (responses_tx, mut responses_rx) = tokio::sync::mpsc::unbounded_channel();
+    // Subscribe before asking, so that no change falls in between.
+    let mut status = state.status.subscribe();
+    // The latest search or browse; the client only wants the newest listing.
+    let mut listing: Option<tokio::task::JoinHandle<()>> = None;
+    match crate::player_status(&state).await {
+        Ok(current) => {
+            if let Err(error) = send(&mut socket, &proto::ServerMessage::Status(current)).await {
+                debug!(message = "websocket send failed", %error);
+                return;
+            }
+        }
+        // Changes still come.
+        Err(error) => warn!(message = "cannot get player status", ?error),
+    }
+
+    loop {
+        let result = tokio::select! {
+            received = socket.recv() => {
+                let text = match received {
+                    None | Some(Ok(Message::Close(_))) => break,
+                    Some(Err(error)) => {
+                        debug!(message = "websocket error", %error);
+                        break;
+                    }
+                    Some(Ok(Message::Text(text))) => text,
+                    // Pings are answered by the library.
+                    Some(Ok(_)) => continue,
+                };
+                let message: proto::ClientMessage = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(error) => {
+                        warn!(message = "bad websocket message", %error);
+                        if let Some(id) = message_id(&text) {
+                            let error = ApiError::new(
+                                proto::ErrorCode::BadRequest,
+                                format!("bad message: {}", error),
+                            );
+                            // The receiver is ours, and still there.
+                            let _ = responses_tx.send(proto::ServerMessage::Response {
+                                id,
+                                response: proto::Response::Error(error.into()),
+                            });
+                        }
+                        continue;
+                    }
+                };
+                let is_listing = matches!(
+                    message.request,
+                    proto::Request::Search { .. } | proto::Request::Browse { .. }
+                );
+                let state = Arc::clone(&state);
+                let responses_tx = responses_tx.clone();
+                let task = tokio::spawn(async move {
+                    let response = respond(state, message.request).await;
+                    // The socket may be gone already, and nobody is waiting.
+                    let _ = responses_tx.send(proto::ServerMessage::Response {
+                        id: message.id,
+                        response,
+                    });
+                });
+                if is_listing {
+                    if let Some(previous) = listing.replace(task) {
+                        previous.abort();
+                    }
+                }
+                Ok(())
+            }
+            Some(message) = responses_rx.recv() => send(&mut socket, &message).await,
+            changed = status.recv() => match changed {
+                Ok(current) => send(&mut socket, &proto::ServerMessage::Status(current)).await,
+                // Only the latest status matters, and it is still coming.
+                Err(RecvError::Lagged(count)) => {
+                    debug!(message = "websocket status lagged", count);
+                    Ok(())
+                }
+                // `State` keeps the sender, as long as the server runs.
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if let Err(error) = result {
+            debug!(message = "websocket send failed", %error);
+            break;
+        }
+    }
+    // Nobody is left to see it.
+    if let Some(listing) = listing {
+        listing.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_of_bad_message() {
+        assert_eq!(message_id(r#"{"id":7,"request":"Nope"}"#), Some(7));
+        assert_eq!(message_id(r#"{"request":"Status"}"#), None);
+        assert_eq!(message_id("not json"), None);
+    }
+}
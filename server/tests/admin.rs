@@ -0,0 +1,115 @@
+//! The admin subcommands, run as the real binary.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn choosy(database: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_choosy"))
+        .arg("--database")
+        .arg(database)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("must start choosy");
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "choosy {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn migrate() {
+    use sleigh::encoding::Encoding;
+
+    // What `Media` looked like on the wire, before and after adding `watched`.
+    #[derive(serde::Serialize)]
+    enum Media {
+        V1 { exists: bool },
+        V2 { exists: bool, watched: bool },
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("db");
+    {
+        let db = sled::open(&database).unwrap();
+        let old = <sleigh::encoding::Bincode>::serialize(&Media::V1 { exists: true }).unwrap();
+        db.open_tree("media").unwrap().insert("a.mkv", old).unwrap();
+        db.flush().unwrap();
+    }
+
+    choosy(&database, &["db", "migrate"], b"");
+
+    let db = sled::open(&database).unwrap();
+    let new = <sleigh::encoding::Bincode>::serialize(&Media::V2 {
+        exists: true,
+        watched: false,
+    })
+    .unwrap();
+    assert_eq!(
+        db.open_tree("media")
+            .unwrap()
+            .get("a.mkv")
+            .unwrap()
+            .as_deref(),
+        Some(&new[..])
+    );
+}
+
+#[test]
+fn convert() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("db");
+    choosy(
+        &database,
+        &["import"],
+        b"{\"path\":\"a.mkv\",\"exists\":true,\"watched\":true}\n",
+    );
+
+    choosy(
+        &database,
+        &[
+            "db",
+            "convert",
+            "--to",
+            "media-json",
+            "--to-encoding",
+            "json",
+        ],
+        b"",
+    );
+
+    let db = sled::open(&database).unwrap();
+    assert_eq!(
+        db.open_tree("media-json")
+            .unwrap()
+            .get("a.mkv")
+            .unwrap()
+            .as_deref(),
+        Some(&br#"{"V2":{"exists":true,"watched":true}}"#[..])
+    );
+}
+
+#[test]
+fn export_then_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let from = dir.path().join("from");
+    let records = "{\"path\":\"a.mkv\",\"exists\":true,\"watched\":true}\n\
+                   {\"path\":\"gone/b.mkv\",\"exists\":false,\"watched\":false}\n";
+    choosy(&from, &["import"], records.as_bytes());
+
+    // Logging is on, and must not end up in the export.
+    let exported = choosy(&from, &["export"], b"").stdout;
+    assert_eq!(std::str::from_utf8(&exported).unwrap(), records);
+
+    let to = dir.path().join("to");
+    choosy(&to, &["import"], &exported);
+    assert_eq!(choosy(&to, &["export"], b"").stdout, exported);
+}
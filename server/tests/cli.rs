@@ -0,0 +1,121 @@
+//! `choosy-cli` against a real server, playing with fake mpv.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+// Stops the server when the test ends, passing or not.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ignore_error = self.0.kill();
+        let _ignore_error = self.0.wait();
+    }
+}
+
+fn start_server(dir: &Path, fake_mpv: &Path) -> (Server, String) {
+    let media = dir.join("media");
+    std::fs::create_dir(&media).unwrap();
+    for filename in ["known.mkv", "other.mkv"] {
+        std::fs::write(media.join(filename), b"").unwrap();
+    }
+    // No `auth`, like the example config.
+    let config = dir.join("config.ron");
+    std::fs::write(
+        &config,
+        format!(
+            r#"ChoosyConfig(
+                path: {:?},
+                fullscreen: false,
+                keep_player: true,
+                mpv_executable: Some({:?}),
+                mpv_env: {{
+                    "FAKE_MPV_SCRIPT": "[]",
+                }},
+            )"#,
+            media, fake_mpv,
+        ),
+    )
+    .unwrap();
+    // Racy, but nothing else here is grabbing ports.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_choosy"))
+        .arg("--config")
+        .arg(&config)
+        .arg("--database")
+        .arg(dir.join("db"))
+        .arg("--listen")
+        .arg(addr.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("must start choosy");
+    (Server(child), format!("http://{}", addr))
+}
+
+fn cli(choosy_cli: &Path, url: &str, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(choosy_cli)
+        .arg("--url")
+        .arg(url)
+        .args(args)
+        .env_remove("CHOOSY_TOKEN")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("must start choosy-cli");
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "choosy-cli failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn search_play_and_control() {
+    let choosy_cli = fake_mpv::build_bin("choosy_cli", "choosy-cli");
+    let dir = tempfile::tempdir().unwrap();
+    let (_server, url) = start_server(dir.path(), fake_mpv::path());
+    let cli = |args: &[&str], stdin: &[u8]| cli(&choosy_cli, &url, args, stdin);
+
+    // Until the server is up, and has scanned the media directory.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let found = "known.mkv\nother.mkv\n";
+    while cli(&["search", "mkv"], b"").stdout != found.as_bytes() {
+        assert!(Instant::now() < deadline, "timeout waiting for choosy");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    stdout(cli(&["play"], found.as_bytes()));
+    assert_eq!(
+        stdout(cli(&["status"], b"")),
+        "playing\t0.0\t3600.0\tknown.mkv\n"
+    );
+    stdout(cli(&["pause"], b""));
+    stdout(cli(&["seek", "1:30"], b""));
+    stdout(cli(&["seek", "-30"], b""));
+    assert_eq!(
+        stdout(cli(&["status"], b"")),
+        "paused\t60.0\t3600.0\tknown.mkv\n"
+    );
+    stdout(cli(&["queue", "other.mkv"], b""));
+
+    let output = cli(&["play", "unknown.mkv"], b"");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("NotFound"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
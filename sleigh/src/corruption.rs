@@ -0,0 +1,200 @@
+//! What to do when the merge operator finds data it cannot handle.
+//!
+//! The merge operator runs inside sled, with no way to return an error, so it has to decide on its own.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub enum CorruptionPolicy {
+    /// Panic, which takes down whatever thread sled was merging on.
+    Panic,
+    /// Copy the bytes that would otherwise be lost into this tree, under `CorruptionKind::quarantine_key`, and continue as with `ResetToDefault`.
+    /// Only the latest bad bytes for each key and kind are kept.
+    Quarantine(sled::Tree),
+    /// Forget the corrupt value and apply the update to `V::default()`.
+    ResetToDefault,
+    /// Leave the corrupt value as it is, and drop the update.
+    KeepOld,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The stored value cannot be deserialized.
+    Stored,
+    /// The update cannot be deserialized.
+    Update,
+    /// The merged value cannot be serialized, e.g. because it grew too big.
+    Serialize,
+}
+
+impl CorruptionKind {
+    /// Where `CorruptionPolicy::Quarantine` keeps the bytes for `key`: the key, prefixed with the kind.
+    ///
+    /// - `Stored`: the stored value
+    /// - `Update`: the update
+    /// - `Serialize`: the update that could not be applied; nothing is quarantined when rewriting, as the stored value is kept
+    pub fn quarantine_key(&self, key: &[u8]) -> Vec<u8> {
+        let prefix: &[u8] = match self {
+            CorruptionKind::Stored => b"stored:",
+            CorruptionKind::Update => b"update:",
+            CorruptionKind::Serialize => b"serialize:",
+        };
+        [prefix, key].concat()
+    }
+}
+
+/// A report of corruption, passed to the callback.
+///
+/// A corrupt update, or a merged value that cannot be serialized, always leaves the stored value untouched, whatever the policy; there is nothing better to store.
+/// The policy decides what happens to a corrupt stored value.
+#[derive(Debug)]
+pub struct Corruption<'a> {
+    /// The raw key, which may not decode either.
+    pub key: &'a [u8],
+    pub kind: CorruptionKind,
+    pub error: &'a (dyn std::error::Error + 'a),
+}
+
+pub type CorruptionCallback = Box<dyn Fn(&Corruption) + Send + Sync>;
+
+pub(crate) struct Handler {
+    pub(crate) policy: CorruptionPolicy,
+    callback: Option<CorruptionCallback>,
+    count: AtomicU64,
+}
+
+impl Handler {
+    pub(crate) fn new(policy: CorruptionPolicy, callback: Option<CorruptionCallback>) -> Self {
+        Handler {
+            policy,
+            callback,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // `bytes` are what would be lost, as listed in `CorruptionKind::quarantine_key`.
+    pub(crate) fn report(
+        &self,
+        key: &[u8],
+        kind: CorruptionKind,
+        error: &dyn std::error::Error,
+        bytes: Option<&[u8]>,
+    ) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Some(callback) = &self.callback {
+            callback(&Corruption { key, kind, error });
+        }
+        match &self.policy {
+            CorruptionPolicy::Panic => {
+                panic!(
+                    "database has corrupt item: key={:?}: {:?}: {}",
+                    key, kind, error
+                )
+            }
+            CorruptionPolicy::Quarantine(tree) => {
+                if let Some(bytes) = bytes {
+                    // If this fails too, the callback has at least heard about it.
+                    let _ignore_error = tree.insert(kind.quarantine_key(key), bytes);
+                }
+            }
+            CorruptionPolicy::ResetToDefault | CorruptionPolicy::KeepOld => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::Bincode, Merge, MergeVerdict, Tree};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, PartialEq)]
+    struct Counter {
+        n: u64,
+    }
+
+    impl Merge<u64> for Counter {
+        fn merge(&mut self, add: u64) -> MergeVerdict {
+            self.n += add;
+            MergeVerdict::Keep
+        }
+    }
+
+    type CounterTree = Tree<String, Counter, u64, Bincode>;
+
+    // An unterminated varint, which bincode cannot decode.
+    const GARBAGE: &[u8] = &[0xff];
+    const OTHER_GARBAGE: &[u8] = &[0xfe];
+
+    fn setup(
+        policy: CorruptionPolicy,
+    ) -> (sled::Tree, CounterTree, Arc<Mutex<Vec<CorruptionKind>>>) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let raw = db.open_tree("counters").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let tree = {
+            let seen = seen.clone();
+            CounterTree::with_corruption_policy(raw.clone(), policy, move |corruption| {
+                seen.lock().unwrap().push(corruption.kind)
+            })
+        };
+        (raw, tree, seen)
+    }
+
+    #[test]
+    fn reset_to_default() {
+        let (raw, tree, seen) = setup(CorruptionPolicy::ResetToDefault);
+        raw.insert("a", GARBAGE).unwrap();
+        tree.merge(&"a".to_string(), &3).unwrap();
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), Some(Counter { n: 3 }));
+        assert_eq!(*seen.lock().unwrap(), vec![CorruptionKind::Stored]);
+        assert_eq!(tree.corruption_count(), 1);
+    }
+
+    #[test]
+    fn keep_old() {
+        let (raw, tree, seen) = setup(CorruptionPolicy::KeepOld);
+        raw.insert("a", GARBAGE).unwrap();
+        tree.merge(&"a".to_string(), &3).unwrap();
+        assert_eq!(raw.get("a").unwrap().as_deref(), Some(GARBAGE));
+        assert_eq!(*seen.lock().unwrap(), vec![CorruptionKind::Stored]);
+    }
+
+    #[test]
+    fn quarantine() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let quarantine = db.open_tree("quarantine").unwrap();
+        let (raw, tree, _seen) = setup(CorruptionPolicy::Quarantine(quarantine.clone()));
+        raw.insert("a", GARBAGE).unwrap();
+        tree.merge(&"a".to_string(), &3).unwrap();
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), Some(Counter { n: 3 }));
+        assert_eq!(
+            quarantine.get("stored:a").unwrap().as_deref(),
+            Some(GARBAGE)
+        );
+
+        // A bad update for the same key does not replace the bad value.
+        raw.merge("a", OTHER_GARBAGE).unwrap();
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), Some(Counter { n: 3 }));
+        assert_eq!(
+            quarantine.get("stored:a").unwrap().as_deref(),
+            Some(GARBAGE)
+        );
+        assert_eq!(
+            quarantine.get("update:a").unwrap().as_deref(),
+            Some(OTHER_GARBAGE)
+        );
+    }
+
+    #[test]
+    fn corrupt_update_keeps_value() {
+        let (raw, tree, seen) = setup(CorruptionPolicy::ResetToDefault);
+        tree.merge(&"a".to_string(), &3).unwrap();
+        raw.merge("a", GARBAGE).unwrap();
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), Some(Counter { n: 3 }));
+        assert_eq!(*seen.lock().unwrap(), vec![CorruptionKind::Update]);
+    }
+}
@@ -1,3 +1,10 @@
+//! How items are turned into bytes.
+//!
+//! Every encoding takes a size limit in bytes, per tree: `Tree<K, V, U, Bincode<100_000>>`.
+//! Items over the limit fail to serialize or deserialize.
+//!
+//! The default limit needs the type written as `<Bincode>` in expressions, e.g. `<Bincode>::serialize(&item)`.
+
 pub trait Encoding {
     type Error: std::error::Error;
 
@@ -6,9 +13,10 @@ pub trait Encoding {
     fn deserialize<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, Self::Error>;
 }
 
+pub const DEFAULT_LIMIT: u64 = 10000;
+
 mod bincode {
     use bincode::Options;
-    use lazy_static::lazy_static;
 
     // WART bincode makes this very, very, ugly.
     // At least this way, the compiler yells at me if I get the repeated things wrong.
@@ -26,26 +34,220 @@ mod bincode {
         >,
         bincode::config::BigEndian,
     >;
-    lazy_static! {
-        static ref BINCODE_OPTIONS: MyBincodeOptions = bincode::DefaultOptions::new()
-            .with_limit(10000)
+
+    fn options(limit: u64) -> MyBincodeOptions {
+        bincode::DefaultOptions::new()
+            .with_limit(limit)
             .reject_trailing_bytes()
             .with_varint_encoding()
-            .with_big_endian();
+            .with_big_endian()
     }
 
-    pub struct Bincode {}
+    /// Compact, but opaque; and the default.
+    pub struct Bincode<const LIMIT: u64 = { super::DEFAULT_LIMIT }> {}
 
-    impl super::Encoding for Bincode {
+    impl<const LIMIT: u64> super::Encoding for Bincode<LIMIT> {
         type Error = bincode::Error;
 
         fn serialize<S: ?Sized + serde::Serialize>(t: &S) -> bincode::Result<Vec<u8>> {
-            BINCODE_OPTIONS.serialize(t)
+            options(LIMIT).serialize(t)
         }
 
         fn deserialize<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
-            BINCODE_OPTIONS.deserialize(bytes)
+            options(LIMIT).deserialize(bytes)
         }
     }
 }
 pub use self::bincode::Bincode;
+
+#[cfg(feature = "json")]
+mod json {
+    use serde::de::Error as _;
+
+    /// Human-inspectable, e.g. in a dump of the raw tree; and the biggest.
+    pub struct Json<const LIMIT: u64 = { super::DEFAULT_LIMIT }> {}
+
+    impl<const LIMIT: u64> super::Encoding for Json<LIMIT> {
+        type Error = serde_json::Error;
+
+        fn serialize<S: ?Sized + serde::Serialize>(t: &S) -> serde_json::Result<Vec<u8>> {
+            let buf = serde_json::to_vec(t)?;
+            if buf.len() as u64 > LIMIT {
+                return Err(<serde_json::Error as serde::ser::Error>::custom(format!(
+                    "item too big: {} > {}",
+                    buf.len(),
+                    LIMIT
+                )));
+            }
+            Ok(buf)
+        }
+
+        fn deserialize<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> serde_json::Result<T> {
+            if bytes.len() as u64 > LIMIT {
+                return Err(serde_json::Error::invalid_length(
+                    bytes.len(),
+                    &format!("at most {} bytes", LIMIT).as_str(),
+                ));
+            }
+            serde_json::from_slice(bytes)
+        }
+    }
+}
+#[cfg(feature = "json")]
+pub use self::json::Json;
+
+#[cfg(feature = "cbor")]
+mod cbor {
+    use serde::de::Error as _;
+
+    /// Self-describing like JSON, about as compact as bincode.
+    pub struct Cbor<const LIMIT: u64 = { super::DEFAULT_LIMIT }> {}
+
+    impl<const LIMIT: u64> super::Encoding for Cbor<LIMIT> {
+        type Error = serde_cbor::Error;
+
+        fn serialize<S: ?Sized + serde::Serialize>(t: &S) -> serde_cbor::Result<Vec<u8>> {
+            // RUST-WART `serde_cbor::to_vec` wants `S: Sized`.
+            let mut buf = Vec::new();
+            t.serialize(&mut serde_cbor::Serializer::new(
+                serde_cbor::ser::IoWrite::new(&mut buf),
+            ))?;
+            if buf.len() as u64 > LIMIT {
+                return Err(<serde_cbor::Error as serde::ser::Error>::custom(format!(
+                    "item too big: {} > {}",
+                    buf.len(),
+                    LIMIT
+                )));
+            }
+            Ok(buf)
+        }
+
+        fn deserialize<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> serde_cbor::Result<T> {
+            if bytes.len() as u64 > LIMIT {
+                return Err(serde_cbor::Error::invalid_length(
+                    bytes.len(),
+                    &format!("at most {} bytes", LIMIT).as_str(),
+                ));
+            }
+            serde_cbor::from_slice(bytes)
+        }
+    }
+}
+#[cfg(feature = "cbor")]
+pub use self::cbor::Cbor;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConvertError<FromError: std::error::Error + 'static, ToError: std::error::Error + 'static>
+{
+    #[error("error deserializing item: key={key:?}: {error}")]
+    Deserialize {
+        key: Vec<u8>,
+        #[source]
+        error: FromError,
+    },
+
+    #[error("error serializing item: key={key:?}: {error}")]
+    Serialize {
+        key: Vec<u8>,
+        #[source]
+        error: ToError,
+    },
+
+    #[error("database error: {0}")]
+    DB(#[from] sled::Error),
+}
+
+/// Copy every item of `from`, stored as `V` in encoding `FromEnc`, into `to`, in encoding `ToEnc`.
+/// Returns how many items were copied.
+///
+/// `to` should be a different, empty tree; rename trees around it if needed.
+/// Conversion stops at the first item that does not survive the trip.
+pub fn convert<V, FromEnc, ToEnc>(
+    from: &sled::Tree,
+    to: &sled::Tree,
+) -> Result<u64, ConvertError<FromEnc::Error, ToEnc::Error>>
+where
+    V: serde::Serialize + for<'de> serde::Deserialize<'de>,
+    FromEnc: Encoding,
+    ToEnc: Encoding,
+    FromEnc::Error: 'static,
+    ToEnc::Error: 'static,
+{
+    let mut count = 0;
+    for result in from.iter() {
+        let (key, buf) = result?;
+        let item: V = FromEnc::deserialize(&buf).map_err(|error| ConvertError::Deserialize {
+            key: key.to_vec(),
+            error,
+        })?;
+        let buf = ToEnc::serialize(&item).map_err(|error| ConvertError::Serialize {
+            key: key.to_vec(),
+            error,
+        })?;
+        to.insert(key, buf)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Track {
+        title: String,
+        seconds: u32,
+    }
+
+    fn tracks(n: usize) -> Vec<Track> {
+        (0..n)
+            .map(|i| Track {
+                title: format!("track {}", i),
+                seconds: 180,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limit() {
+        let big = tracks(1000);
+        assert!(<Bincode>::serialize(&big).is_err());
+        let buf = Bincode::<100_000>::serialize(&big).unwrap();
+        let got: Vec<Track> = Bincode::<100_000>::deserialize(&buf).unwrap();
+        assert_eq!(got, big);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let buf = <Json>::serialize(&tracks(1)).unwrap();
+        assert_eq!(buf, br#"[{"title":"track 0","seconds":180}]"#);
+        assert!(<Json>::serialize(&tracks(1000)).is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        let buf = <Cbor>::serialize(&tracks(2)).unwrap();
+        let got: Vec<Track> = <Cbor>::deserialize(&buf).unwrap();
+        assert_eq!(got, tracks(2));
+        assert!(<Cbor>::serialize(&tracks(1000)).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn convert_to_json() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let from = db.open_tree("bincode").unwrap();
+        let to = db.open_tree("json").unwrap();
+        from.insert("a", <Bincode>::serialize(&tracks(1)).unwrap())
+            .unwrap();
+        let count = convert::<Vec<Track>, Bincode, Json>(&from, &to).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            to.get("a").unwrap().unwrap(),
+            br#"[{"title":"track 0","seconds":180}]"#
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Secondary indexes: trees from something computed from each item, back to the item's key.
+//!
+//! Once added with `Tree::with_index`, an index is kept up to date by `insert`, `merge`, `apply_batch` and transactions, all of which then run as transactions over the tree and its indexes.
+//! Writes that bypass sleigh, or corrupt items, may leave stale entries behind; look up the items to be sure.
+
+use crate::{Key, KeyError};
+use std::ops::{Bound, RangeBounds};
+
+/// An index of items with keys of type `K`, by `IK`.
+///
+/// Entries are stored as `(IK, K)`, so items with the same `IK` are ordered by their key.
+pub struct Index<IK, K> {
+    pub(crate) tree: sled::Tree,
+    _phantom_ik: std::marker::PhantomData<IK>,
+    _phantom_k: std::marker::PhantomData<K>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IndexError {
+    #[error("error decoding key: {0}")]
+    Key(#[source] KeyError),
+
+    #[error("database error: {0}")]
+    DB(#[from] sled::Error),
+}
+
+impl<IK: Key, K: Key> Index<IK, K> {
+    pub fn new(tree: sled::Tree) -> Self {
+        Index {
+            tree,
+            _phantom_ik: std::marker::PhantomData,
+            _phantom_k: std::marker::PhantomData,
+        }
+    }
+
+    pub fn iter(&self) -> IndexIter<IK, K> {
+        IndexIter::new(self.tree.iter())
+    }
+
+    /// Entries whose `IK` is in `range`.
+    pub fn range<R: RangeBounds<IK>>(&self, range: R) -> IndexIter<IK, K> {
+        let start = match range.start_bound() {
+            Bound::Included(ik) => Bound::Included(prefix(ik)),
+            Bound::Excluded(ik) => match successor(prefix(ik)) {
+                Some(after) => Bound::Included(after),
+                // Nothing sorts after it; make the range empty.
+                None => Bound::Excluded(prefix(ik)),
+            },
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(ik) => match successor(prefix(ik)) {
+                Some(after) => Bound::Excluded(after),
+                None => Bound::Unbounded,
+            },
+            Bound::Excluded(ik) => Bound::Excluded(prefix(ik)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        IndexIter::new(self.tree.range((start, end)))
+    }
+}
+
+fn prefix<IK: Key>(ik: &IK) -> Vec<u8> {
+    let mut out = Vec::new();
+    ik.write_key(&mut out, false);
+    out
+}
+
+// The smallest byte string greater than everything starting with `prefix`, if any.
+fn successor(mut prefix: Vec<u8>) -> Option<Vec<u8>> {
+    while let Some(last) = prefix.pop() {
+        if last != 0xFF {
+            prefix.push(last + 1);
+            return Some(prefix);
+        }
+    }
+    None
+}
+
+pub struct IndexIter<IK, K> {
+    iter: sled::Iter,
+    _phantom_ik: std::marker::PhantomData<IK>,
+    _phantom_k: std::marker::PhantomData<K>,
+}
+
+impl<IK: Key, K: Key> IndexIter<IK, K> {
+    fn new(iter: sled::Iter) -> Self {
+        IndexIter {
+            iter,
+            _phantom_ik: std::marker::PhantomData,
+            _phantom_k: std::marker::PhantomData,
+        }
+    }
+
+    fn decode(result: sled::Result<(sled::IVec, sled::IVec)>) -> Result<(IK, K), IndexError> {
+        let (entry, _empty) = result?;
+        crate::key::decode::<(IK, K)>(&entry).map_err(IndexError::Key)
+    }
+}
+
+impl<IK: Key, K: Key> Iterator for IndexIter<IK, K> {
+    type Item = Result<(IK, K), IndexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(Self::decode)
+    }
+}
+
+impl<IK: Key, K: Key> DoubleEndedIterator for IndexIter<IK, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(Self::decode)
+    }
+}
+
+// Encoded `IK`, ready to have the encoded key appended.
+type PrefixFn<V> = Box<dyn Fn(&V) -> Option<Vec<u8>> + Send + Sync>;
+
+// What a `Tree` needs to know to maintain an index.
+pub(crate) struct Hook<V> {
+    pub(crate) tree: sled::Tree,
+    prefix: PrefixFn<V>,
+}
+
+impl<V> Hook<V> {
+    pub(crate) fn new<IK, F>(tree: sled::Tree, f: F) -> Self
+    where
+        IK: Key,
+        F: 'static + Fn(&V) -> Option<IK> + Send + Sync,
+    {
+        Hook {
+            tree,
+            prefix: Box::new(move |item| f(item).map(|ik| prefix(&ik))),
+        }
+    }
+
+    // `key` is encoded; as the last part of `(IK, K)`, its encoding is the same.
+    pub(crate) fn entry(&self, key: &[u8], item: &V) -> Option<Vec<u8>> {
+        let mut entry = (self.prefix)(item)?;
+        entry.extend_from_slice(key);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::Bincode, Batch, Merge, MergeVerdict, Tree, TxError};
+
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, PartialEq)]
+    struct Video {
+        last_played: Option<u64>,
+    }
+
+    impl Merge<u64> for Video {
+        fn merge(&mut self, played: u64) -> MergeVerdict {
+            self.last_played = Some(played);
+            MergeVerdict::Keep
+        }
+    }
+
+    type Videos = Tree<String, Video, u64, Bincode>;
+
+    fn open() -> (Videos, Index<u64, String>) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let by_played = Index::new(db.open_tree("by-played").unwrap());
+        let videos = Videos::new(db.open_tree("videos").unwrap())
+            .with_index(&by_played, |video: &Video| video.last_played)
+            .unwrap();
+        (videos, by_played)
+    }
+
+    fn entries(
+        iter: impl Iterator<Item = Result<(u64, String), IndexError>>,
+    ) -> Vec<(u64, String)> {
+        iter.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn maintained_by_writes() {
+        let (videos, by_played) = open();
+        videos.insert(&"a".to_string(), &Video::default()).unwrap();
+        videos
+            .insert(
+                &"b".to_string(),
+                &Video {
+                    last_played: Some(10),
+                },
+            )
+            .unwrap();
+        videos.merge(&"a".to_string(), &20).unwrap();
+        videos.merge(&"b".to_string(), &30).unwrap();
+        let mut batch = Batch::new();
+        batch
+            .insert(
+                &"c".to_string(),
+                &Video {
+                    last_played: Some(5),
+                },
+            )
+            .unwrap();
+        videos.apply_batch(batch).unwrap();
+        videos
+            .transaction(|tx| {
+                tx.merge(&"c".to_string(), 25)?;
+                Ok::<_, TxError<(), _>>(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            entries(by_played.iter().rev()),
+            vec![
+                (30, "b".to_string()),
+                (25, "c".to_string()),
+                (20, "a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_with_item() {
+        let (videos, by_played) = open();
+        videos.merge(&"a".to_string(), &1).unwrap();
+        videos.merge(&"b".to_string(), &2).unwrap();
+        videos.remove(&"a".to_string()).unwrap();
+        assert_eq!(entries(by_played.iter()), vec![(2, "b".to_string())]);
+    }
+
+    #[test]
+    fn range() {
+        let (videos, by_played) = open();
+        for (name, played) in &[("a", 1), ("b", 2), ("c", 2), ("d", 3)] {
+            videos
+                .insert(
+                    &name.to_string(),
+                    &Video {
+                        last_played: Some(*played),
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            entries(by_played.range(2..=2)),
+            vec![(2, "b".to_string()), (2, "c".to_string())]
+        );
+        assert_eq!(entries(by_played.range(..2)), vec![(1, "a".to_string())]);
+        assert_eq!(
+            entries(by_played.range((Bound::Excluded(2), Bound::Unbounded))),
+            vec![(3, "d".to_string())]
+        );
+    }
+
+    #[test]
+    fn built_for_existing_items() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let videos = Videos::new(db.open_tree("videos").unwrap());
+        videos
+            .insert(
+                &"a".to_string(),
+                &Video {
+                    last_played: Some(1),
+                },
+            )
+            .unwrap();
+        let by_played = Index::new(db.open_tree("by-played").unwrap());
+        let _videos = videos
+            .with_index(&by_played, |video: &Video| video.last_played)
+            .unwrap();
+        assert_eq!(entries(by_played.iter()), vec![(1, "a".to_string())]);
+    }
+}
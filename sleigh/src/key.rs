@@ -0,0 +1,305 @@
+//! Typed keys, encoded so that sled's bytewise order is the same as the order of the values.
+
+use std::convert::TryInto;
+use std::ops::{Bound, RangeBounds};
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("key is truncated")]
+    Truncated,
+    #[error("key has trailing bytes")]
+    Trailing,
+    #[error("key is not UTF-8: {0}")]
+    NotUTF8(#[from] std::str::Utf8Error),
+}
+
+/// A key of a `Tree`.
+///
+/// Encodings must preserve order: for any `a` and `b`, `a.cmp(&b)` must equal the bytewise comparison of their encodings.
+pub trait Key: Sized {
+    /// Append the encoding of `self` to `out`.
+    /// If `last` is true, nothing will follow it, and the encoding need not be self-delimiting.
+    fn write_key(&self, out: &mut Vec<u8>, last: bool);
+
+    /// Decode a key written with the same `last`, returning the bytes after it.
+    fn read_key(bytes: &[u8], last: bool) -> Result<(Self, &[u8]), KeyError>;
+}
+
+/// A prefix of keys of type `K`, for `Tree::scan_prefix`.
+pub trait Prefix<K: Key> {
+    fn write_prefix(&self, out: &mut Vec<u8>);
+}
+
+pub(crate) fn encode<K: Key>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.write_key(&mut out, true);
+    out
+}
+
+pub(crate) fn decode<K: Key>(bytes: &[u8]) -> Result<K, KeyError> {
+    let (key, rest) = K::read_key(bytes, true)?;
+    if !rest.is_empty() {
+        return Err(KeyError::Trailing);
+    }
+    Ok(key)
+}
+
+pub(crate) fn encode_prefix<K: Key, P: ?Sized + Prefix<K>>(prefix: &P) -> Vec<u8> {
+    let mut out = Vec::new();
+    prefix.write_prefix(&mut out);
+    out
+}
+
+pub(crate) fn encode_range<K: Key, R: RangeBounds<K>>(
+    range: &R,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let encode_bound = |bound: Bound<&K>| match bound {
+        Bound::Included(key) => Bound::Included(encode(key)),
+        Bound::Excluded(key) => Bound::Excluded(encode(key)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (
+        encode_bound(range.start_bound()),
+        encode_bound(range.end_bound()),
+    )
+}
+
+// Every key is a prefix of itself, and of nothing else; except strings, see below.
+impl<K: Key> Prefix<K> for K {
+    fn write_prefix(&self, out: &mut Vec<u8>) {
+        self.write_key(out, true)
+    }
+}
+
+// Byte strings that are not last in a key escape `0x00` as `0x00 0xFF` and end in `0x00 0x01`.
+// The terminator sorts before any continuation, so a string sorts before all strings it's a prefix of.
+// As the last part of a key, they are stored as is; this keeps plain `String` keys readable by anything that knows sled.
+fn write_bytes(bytes: &[u8], out: &mut Vec<u8>, last: bool) {
+    if last {
+        out.extend_from_slice(bytes);
+        return;
+    }
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0x00, 0x01]);
+}
+
+fn read_bytes(bytes: &[u8], last: bool) -> Result<(Vec<u8>, &[u8]), KeyError> {
+    if last {
+        return Ok((bytes.to_vec(), &[]));
+    }
+    let mut value = Vec::new();
+    let mut rest = bytes;
+    loop {
+        match rest {
+            [0x00, 0x01, tail @ ..] => return Ok((value, tail)),
+            [0x00, 0xFF, tail @ ..] => {
+                value.push(0x00);
+                rest = tail;
+            }
+            [0x00, ..] | [] => return Err(KeyError::Truncated),
+            [b, tail @ ..] => {
+                value.push(*b);
+                rest = tail;
+            }
+        }
+    }
+}
+
+impl Key for Vec<u8> {
+    fn write_key(&self, out: &mut Vec<u8>, last: bool) {
+        write_bytes(self, out, last)
+    }
+
+    fn read_key(bytes: &[u8], last: bool) -> Result<(Self, &[u8]), KeyError> {
+        read_bytes(bytes, last)
+    }
+}
+
+impl Key for String {
+    fn write_key(&self, out: &mut Vec<u8>, last: bool) {
+        write_bytes(self.as_bytes(), out, last)
+    }
+
+    fn read_key(bytes: &[u8], last: bool) -> Result<(Self, &[u8]), KeyError> {
+        let (value, rest) = read_bytes(bytes, last)?;
+        let value = String::from_utf8(value).map_err(|error| error.utf8_error())?;
+        Ok((value, rest))
+    }
+}
+
+/// Any string prefix, as in `scan_prefix("Anime/")`.
+impl Prefix<String> for str {
+    fn write_prefix(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes())
+    }
+}
+
+// Integers are big-endian, with the sign bit flipped so negative numbers sort first.
+macro_rules! impl_key_int {
+    ($($t:ty => $sign:expr),* $(,)?) => {
+        $(
+            impl Key for $t {
+                fn write_key(&self, out: &mut Vec<u8>, _last: bool) {
+                    let flipped = *self ^ $sign;
+                    out.extend_from_slice(&flipped.to_be_bytes());
+                }
+
+                fn read_key(bytes: &[u8], _last: bool) -> Result<(Self, &[u8]), KeyError> {
+                    const SIZE: usize = std::mem::size_of::<$t>();
+                    if bytes.len() < SIZE {
+                        return Err(KeyError::Truncated);
+                    }
+                    let (head, rest) = bytes.split_at(SIZE);
+                    let flipped = <$t>::from_be_bytes(head.try_into().unwrap());
+                    Ok((flipped ^ $sign, rest))
+                }
+            }
+        )*
+    };
+}
+
+impl_key_int!(
+    u8 => 0,
+    u16 => 0,
+    u32 => 0,
+    u64 => 0,
+    i8 => i8::MIN,
+    i16 => i16::MIN,
+    i32 => i32::MIN,
+    i64 => i64::MIN,
+);
+
+impl<A: Key, B: Key> Key for (A, B) {
+    fn write_key(&self, out: &mut Vec<u8>, last: bool) {
+        self.0.write_key(out, false);
+        self.1.write_key(out, last);
+    }
+
+    fn read_key(bytes: &[u8], last: bool) -> Result<(Self, &[u8]), KeyError> {
+        let (a, rest) = A::read_key(bytes, false)?;
+        let (b, rest) = B::read_key(rest, last)?;
+        Ok(((a, b), rest))
+    }
+}
+
+impl<A: Key, B: Key, C: Key> Key for (A, B, C) {
+    fn write_key(&self, out: &mut Vec<u8>, last: bool) {
+        self.0.write_key(out, false);
+        self.1.write_key(out, false);
+        self.2.write_key(out, last);
+    }
+
+    fn read_key(bytes: &[u8], last: bool) -> Result<(Self, &[u8]), KeyError> {
+        let (a, rest) = A::read_key(bytes, false)?;
+        let (b, rest) = B::read_key(rest, false)?;
+        let (c, rest) = C::read_key(rest, last)?;
+        Ok(((a, b, c), rest))
+    }
+}
+
+/// All keys starting with the given first element.
+impl<A: Key, B: Key> Prefix<(A, B)> for (A,) {
+    fn write_prefix(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out, false);
+    }
+}
+
+/// All keys starting with the given first element.
+impl<A: Key, B: Key, C: Key> Prefix<(A, B, C)> for (A,) {
+    fn write_prefix(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out, false);
+    }
+}
+
+/// All keys starting with the given two elements.
+impl<A: Key, B: Key, C: Key> Prefix<(A, B, C)> for (A, B) {
+    fn write_prefix(&self, out: &mut Vec<u8>) {
+        self.0.write_key(out, false);
+        self.1.write_key(out, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_order<K: Key + Ord + std::fmt::Debug>(keys: &[K]) {
+        for (a, b) in keys.iter().zip(keys.iter().skip(1)) {
+            assert!(a < b, "test keys must be sorted: {:?} {:?}", a, b);
+            assert!(
+                encode(a) < encode(b),
+                "encoding does not preserve order: {:?} {:?}",
+                a,
+                b
+            );
+        }
+        for key in keys {
+            assert_eq!(&decode::<K>(&encode(key)).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn string_is_raw() {
+        assert_eq!(encode(&"foo".to_string()), b"foo");
+    }
+
+    #[test]
+    fn order_signed() {
+        assert_order(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+    }
+
+    #[test]
+    fn order_unsigned() {
+        assert_order(&[0u16, 1, 255, 256, u16::MAX]);
+    }
+
+    #[test]
+    fn order_tuple_of_strings() {
+        assert_order(&[
+            ("".to_string(), "z".to_string()),
+            ("a".to_string(), "".to_string()),
+            ("a".to_string(), "b".to_string()),
+            ("a\0".to_string(), "".to_string()),
+            ("a\0b".to_string(), "".to_string()),
+            ("a\u{1}".to_string(), "".to_string()),
+            ("ab".to_string(), "".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn order_mixed_tuple() {
+        assert_order(&[
+            (-1i32, "b".to_string(), 9u8),
+            (0, "a".to_string(), 1),
+            (0, "a".to_string(), 2),
+            (0, "ab".to_string(), 0),
+        ]);
+    }
+
+    #[test]
+    fn prefix_of_tuple() {
+        let key = ("dir".to_string(), 7u32);
+        let prefix = encode_prefix::<(String, u32), _>(&("dir".to_string(),));
+        assert!(encode(&key).starts_with(&prefix));
+        let other = ("dir2".to_string(), 7u32);
+        assert!(!encode(&other).starts_with(&prefix));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(matches!(
+            decode::<(String, u8)>(b"abc"),
+            Err(KeyError::Truncated)
+        ));
+        assert!(matches!(decode::<u16>(b"abc"), Err(KeyError::Trailing)));
+        assert!(matches!(
+            decode::<String>(b"\xff"),
+            Err(KeyError::NotUTF8(_))
+        ));
+    }
+}
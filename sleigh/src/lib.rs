@@ -1,8 +1,20 @@
-use std::{future::Future, pin::Pin, sync::mpsc, time::Duration};
-
-// TODO make keys typed too.
+use std::{future::Future, ops::RangeBounds, pin::Pin, sync::mpsc, sync::Arc, time::Duration};
 
+pub mod corruption;
+pub use self::corruption::{Corruption, CorruptionKind, CorruptionPolicy};
 pub mod encoding;
+pub mod index;
+pub use self::index::{Index, IndexError, IndexIter};
+pub mod key;
+pub use self::key::{Key, KeyError, Prefix};
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(feature = "async")]
+pub use self::nonblocking::{AsyncTree, Scan};
+pub mod transaction;
+pub use self::transaction::{Batch, TransactionalTree, TxError};
+pub mod versioned;
+pub use self::versioned::Upgrade;
 
 pub enum MergeVerdict {
     Keep,
@@ -12,8 +24,11 @@ pub trait Merge<U> {
     fn merge(&mut self, update: U) -> MergeVerdict;
 }
 
-pub struct Tree<V, U, Enc: self::encoding::Encoding> {
+pub struct Tree<K, V, U, Enc: self::encoding::Encoding> {
     tree: sled::Tree,
+    corruption: Arc<corruption::Handler>,
+    indexes: Vec<index::Hook<V>>,
+    _phantom_k: std::marker::PhantomData<K>,
     _phantom_v: std::marker::PhantomData<V>,
     _phantom_u: std::marker::PhantomData<U>,
     _phantom_enc: std::marker::PhantomData<Enc>,
@@ -33,12 +48,16 @@ pub enum GetError<DeserializeError: 'static + std::error::Error> {
     #[error("error deserializing: {0}")]
     Deserialize(#[source] DeserializeError),
 
+    #[error("error decoding key: {0}")]
+    Key(#[source] KeyError),
+
     #[error("database error: {0}")]
     DB(#[from] sled::Error),
 }
 
-impl<V, U, Enc: 'static + self::encoding::Encoding> Tree<V, U, Enc>
+impl<K, V, U, Enc: 'static + self::encoding::Encoding> Tree<K, V, U, Enc>
 where
+    K: 'static + Key,
     // TODO do i really have to repeat the constraints here?
     V: 'static
         + ?Sized
@@ -48,52 +67,179 @@ where
         + Merge<U>,
     U: 'static + ?Sized + serde::Serialize + for<'de> serde::de::Deserialize<'de>,
 {
+    /// Panics on corrupt data in merges.
     pub fn new(tree: sled::Tree) -> Self {
-        tree.set_merge_operator(Self::merge_operator);
+        Self::with_handler(
+            tree,
+            corruption::Handler::new(CorruptionPolicy::Panic, None),
+        )
+    }
+
+    /// Handle corrupt data in merges according to `policy`, telling `callback` about each case first.
+    pub fn with_corruption_policy<F>(
+        tree: sled::Tree,
+        policy: CorruptionPolicy,
+        callback: F,
+    ) -> Self
+    where
+        F: 'static + Fn(&Corruption) + Send + Sync,
+    {
+        Self::with_handler(
+            tree,
+            corruption::Handler::new(policy, Some(Box::new(callback))),
+        )
+    }
+
+    fn with_handler(tree: sled::Tree, handler: corruption::Handler) -> Self {
+        let corruption = Arc::new(handler);
+        {
+            let corruption = corruption.clone();
+            tree.set_merge_operator(move |key: &[u8], old: Option<&[u8]>, new: &[u8]| {
+                Self::merge_operator(&corruption, key, old, new)
+            });
+        }
         Tree {
             tree,
+            corruption,
+            indexes: Vec::new(),
+            _phantom_k: std::marker::PhantomData,
             _phantom_v: std::marker::PhantomData,
             _phantom_u: std::marker::PhantomData,
             _phantom_enc: std::marker::PhantomData,
         }
     }
 
-    fn merge_operator(key: &[u8], old: Option<&[u8]>, new: &[u8]) -> Option<Vec<u8>> {
+    fn merge_operator(
+        corruption: &corruption::Handler,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Option<Vec<u8>> {
         let mut item = match old {
             None => V::default(),
-            Some(b) => {
-                let item: V = Enc::deserialize(b).unwrap_or_else(|error| {
-                    panic!("database has corrupt item: key={:?}: {}", &key, error)
-                });
-                item
+            Some(b) => match Enc::deserialize::<V>(b) {
+                Ok(item) => item,
+                Err(error) => {
+                    corruption.report(key, CorruptionKind::Stored, &error, Some(b));
+                    match corruption.policy {
+                        CorruptionPolicy::KeepOld => return Some(b.to_vec()),
+                        // Panic already happened in report.
+                        _ => V::default(),
+                    }
+                }
+            },
+        };
+        let ops: U = match Enc::deserialize(new) {
+            Ok(ops) => ops,
+            Err(error) => {
+                corruption.report(key, CorruptionKind::Update, &error, Some(new));
+                return old.map(|b| b.to_vec());
             }
         };
-        let ops: U = Enc::deserialize(new).unwrap_or_else(|error| {
-            panic!("database has corrupt item (#2): key={:?}: {}", &key, error)
-        });
         match item.merge(ops) {
             MergeVerdict::Remove => None,
-            MergeVerdict::Keep => {
-                let buf = Enc::serialize(&item)
-                    .unwrap_or_else(|error| panic!("cannot serialize: {}", error));
-                Some(buf)
+            MergeVerdict::Keep => match Enc::serialize(&item) {
+                Ok(buf) => Some(buf),
+                Err(error) => {
+                    corruption.report(key, CorruptionKind::Serialize, &error, Some(new));
+                    old.map(|b| b.to_vec())
+                }
+            },
+        }
+    }
+
+    /// Rewrite every item not stored the way it would be written now, e.g. after adding a version to a `versioned!` type.
+    /// Items that cannot be read are handled by the corruption policy.
+    /// Returns how many items were rewritten.
+    pub fn rewrite_all(&self) -> Result<u64, sled::Error> {
+        let mut count = 0;
+        for result in self.tree.iter() {
+            let (key, old) = result?;
+            let item = match Enc::deserialize::<V>(&old) {
+                Ok(item) => item,
+                Err(error) => {
+                    self.corruption
+                        .report(&key, CorruptionKind::Stored, &error, Some(&old));
+                    match self.corruption.policy {
+                        CorruptionPolicy::KeepOld => continue,
+                        // Panic already happened in report.
+                        _ => V::default(),
+                    }
+                }
+            };
+            let new = match Enc::serialize(&item) {
+                Ok(buf) => buf,
+                Err(error) => {
+                    // The stored value stays, and there is no update to lose.
+                    self.corruption
+                        .report(&key, CorruptionKind::Serialize, &error, None);
+                    continue;
+                }
+            };
+            if new == *old {
+                continue;
+            }
+            // Losing the race means someone else just wrote it, in the current format.
+            if self
+                .tree
+                .compare_and_swap(&key, Some(&old), Some(new))?
+                .is_ok()
+            {
+                count += 1;
             }
         }
+        Ok(count)
     }
 
-    pub fn insert<K>(&self, key: K, item: &V) -> Result<(), InsertError<Enc::Error>>
+    /// Maintain `index` with the `IK` that `f` computes for each item, if any.
+    /// If the index is empty, it is first built from the existing items.
+    pub fn with_index<IK, F>(mut self, index: &Index<IK, K>, f: F) -> Result<Self, sled::Error>
     where
-        K: AsRef<[u8]>,
+        IK: Key,
+        F: 'static + Fn(&V) -> Option<IK> + Send + Sync,
     {
+        let hook = index::Hook::new(index.tree.clone(), f);
+        if hook.tree.is_empty() {
+            for result in self.tree.iter() {
+                let (key, buf) = result?;
+                // Corrupt items are for the corruption policy to deal with, later.
+                if let Ok(item) = Enc::deserialize::<V>(&buf) {
+                    if let Some(entry) = hook.entry(&key, &item) {
+                        hook.tree.insert(entry, &[])?;
+                    }
+                }
+            }
+        }
+        self.indexes.push(hook);
+        Ok(self)
+    }
+
+    /// How many times merges have run into corrupt data.
+    pub fn corruption_count(&self) -> u64 {
+        self.corruption.count()
+    }
+
+    pub fn insert(&self, key: &K, item: &V) -> Result<(), InsertError<Enc::Error>> {
+        if !self.indexes.is_empty() {
+            return self.transactional_write(|tx| tx.insert(key, item));
+        }
         let buf = Enc::serialize(item).map_err(InsertError::Serialize)?;
-        let _ = self.tree.insert(key, buf)?;
+        let _ = self.tree.insert(key::encode(key), buf)?;
         // For now, we don't bother with returning any old value.
         // That would require a lazy deserialize wrapper.
         Ok(())
     }
 
-    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<V>, GetError<Enc::Error>> {
-        match self.tree.get(key)? {
+    pub fn remove(&self, key: &K) -> Result<(), InsertError<Enc::Error>> {
+        if !self.indexes.is_empty() {
+            return self.transactional_write(|tx| tx.remove(key));
+        }
+        let _ = self.tree.remove(key::encode(key))?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, GetError<Enc::Error>> {
+        match self.tree.get(key::encode(key))? {
             None => Ok(None),
             Some(buf) => {
                 let item: V = Enc::deserialize(&buf).map_err(GetError::Deserialize)?;
@@ -102,62 +248,121 @@ where
         }
     }
 
-    pub fn merge<K>(&self, key: K, update: &U) -> Result<(), InsertError<Enc::Error>>
-    where
-        K: AsRef<[u8]>,
-    {
+    pub fn merge(&self, key: &K, update: &U) -> Result<(), InsertError<Enc::Error>> {
         let buf = Enc::serialize(update).map_err(InsertError::Serialize)?;
-        let _ = self.tree.merge(key, buf)?;
+        if !self.indexes.is_empty() {
+            // Through the transaction, so the indexes see the merged item.
+            return self.transactional_write(|tx| {
+                let update: U = Enc::deserialize(&buf).map_err(TxError::Deserialize)?;
+                tx.merge(key, update)
+            });
+        }
+        let _ = self.tree.merge(key::encode(key), buf)?;
         // For now, we don't bother with returning any old value.
         // That would require a lazy deserialize wrapper.
         Ok(())
     }
 
-    pub fn scan_prefix<P>(&self, prefix: P) -> Iter<V, Enc>
+    /// Iterate over all items, in key order.
+    pub fn iter(&self) -> Iter<K, V, Enc> {
+        Iter::new(self.tree.iter())
+    }
+
+    /// Iterate over the items with keys in `range`, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<K, V, Enc> {
+        Iter::new(self.tree.range(key::encode_range(&range)))
+    }
+
+    /// The item with the smallest key.
+    pub fn first(&self) -> Result<Option<(K, V)>, GetError<Enc::Error>> {
+        self.iter().next().transpose()
+    }
+
+    /// The item with the largest key.
+    pub fn last(&self) -> Result<Option<(K, V)>, GetError<Enc::Error>> {
+        self.iter().next_back().transpose()
+    }
+
+    pub fn scan_prefix<P>(&self, prefix: &P) -> Iter<K, V, Enc>
     where
-        P: AsRef<[u8]>,
+        P: ?Sized + Prefix<K>,
     {
-        let iter = self.tree.scan_prefix(prefix);
-        Iter {
-            iter,
-            _phantom_v: std::marker::PhantomData,
-            _phantom_enc: std::marker::PhantomData,
-        }
+        Iter::new(self.tree.scan_prefix(key::encode_prefix(prefix)))
     }
 
-    pub fn watch_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Subscriber<V, Enc> {
-        let sub = self.tree.watch_prefix(prefix);
+    pub fn watch_prefix<P>(&self, prefix: &P) -> Subscriber<K, V, Enc>
+    where
+        P: ?Sized + Prefix<K>,
+    {
+        let sub = self.tree.watch_prefix(key::encode_prefix(prefix));
         Subscriber {
             sub,
+            _phantom_k: std::marker::PhantomData,
             _phantom_v: std::marker::PhantomData,
             _phantom_enc: std::marker::PhantomData,
         }
     }
 }
 
-pub struct Iter<V, Enc: self::encoding::Encoding> {
+pub struct Iter<K, V, Enc: self::encoding::Encoding> {
     iter: sled::Iter,
+    _phantom_k: std::marker::PhantomData<K>,
     _phantom_v: std::marker::PhantomData<V>,
     _phantom_enc: std::marker::PhantomData<Enc>,
 }
 
-impl<V, Enc: self::encoding::Encoding + 'static> Iterator for Iter<V, Enc>
+impl<K, V, Enc: self::encoding::Encoding> Iter<K, V, Enc> {
+    fn new(iter: sled::Iter) -> Self {
+        Iter {
+            iter,
+            _phantom_k: std::marker::PhantomData,
+            _phantom_v: std::marker::PhantomData,
+            _phantom_enc: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K, V, Enc: self::encoding::Encoding + 'static> Iterator for Iter<K, V, Enc>
 where
+    K: 'static + Key,
     // TODO do i really have to repeat the constraints here?
     V: 'static + ?Sized + for<'de> serde::de::Deserialize<'de>,
 {
-    type Item = Result<(sled::IVec, V), GetError<Enc::Error>>;
+    type Item = Result<(K, V), GetError<Enc::Error>>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        Self::decode(self.iter.next())
+    }
+}
+
+impl<K, V, Enc: self::encoding::Encoding + 'static> DoubleEndedIterator for Iter<K, V, Enc>
+where
+    K: 'static + Key,
+    V: 'static + for<'de> serde::de::Deserialize<'de>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Self::decode(self.iter.next_back())
+    }
+}
+
+impl<K, V, Enc: self::encoding::Encoding + 'static> Iter<K, V, Enc>
+where
+    K: 'static + Key,
+    V: 'static + for<'de> serde::de::Deserialize<'de>,
+{
+    #[allow(clippy::type_complexity)]
+    fn decode(
+        next: Option<sled::Result<(sled::IVec, sled::IVec)>>,
+    ) -> Option<Result<(K, V), GetError<Enc::Error>>> {
         // The layering of Option<Result< makes this awkward code.
-        let result = match self.iter.next() {
-            None => return None,
-            Some(r) => r,
-        };
-        let (key, buf) = match result {
+        let (key, buf) = match next? {
             Err(error) => return Some(Err(GetError::DB(error))),
             Ok((key, buf)) => (key, buf),
         };
+        let key: K = match key::decode(&key) {
+            Err(error) => return Some(Err(GetError::Key(error))),
+            Ok(key) => key,
+        };
         let dec: Result<V, Enc::Error> = Enc::deserialize(&buf);
         match dec {
             Err(error) => Some(Err(GetError::Deserialize(error))),
@@ -166,15 +371,16 @@ where
     }
 }
 
-pub struct Subscriber<V, Enc: self::encoding::Encoding> {
+pub struct Subscriber<K, V, Enc: self::encoding::Encoding> {
     sub: sled::Subscriber,
+    _phantom_k: std::marker::PhantomData<K>,
     _phantom_v: std::marker::PhantomData<V>,
     _phantom_enc: std::marker::PhantomData<Enc>,
 }
 
-pub enum Event<V> {
-    Insert { key: sled::IVec, value: V },
-    Remove { key: sled::IVec },
+pub enum Event<K, V> {
+    Insert { key: K, value: V },
+    Remove { key: K },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -182,22 +388,33 @@ pub enum SubscriberTimeoutError<DeserializeError: 'static + std::error::Error> {
     #[error("error deserializing: {0}")]
     Deserialize(#[source] DeserializeError),
 
+    #[error("error decoding key: {0}")]
+    Key(#[source] KeyError),
+
     #[error(transparent)]
     Recv(#[from] mpsc::RecvTimeoutError),
 }
 
-impl<V, Enc: self::encoding::Encoding + 'static> Subscriber<V, Enc>
+impl<K, V, Enc: self::encoding::Encoding + 'static> Subscriber<K, V, Enc>
 where
+    K: 'static + Key,
     // TODO do i really have to repeat the constraints here?
     V: 'static + ?Sized + for<'de> serde::de::Deserialize<'de>,
 {
-    fn event_from_sled(orig: sled::Event) -> Result<Event<V>, Enc::Error> {
+    fn event_from_sled(
+        orig: sled::Event,
+    ) -> Result<Event<K, V>, SubscriberIteratorError<Enc::Error>> {
         let event = match orig {
             sled::Event::Insert { key, value } => {
-                let item: V = Enc::deserialize(&value)?;
+                let key: K = key::decode(&key).map_err(SubscriberIteratorError::Key)?;
+                let item: V =
+                    Enc::deserialize(&value).map_err(SubscriberIteratorError::Deserialize)?;
                 Event::Insert { key, value: item }
             }
-            sled::Event::Remove { key } => Event::Remove { key },
+            sled::Event::Remove { key } => {
+                let key: K = key::decode(&key).map_err(SubscriberIteratorError::Key)?;
+                Event::Remove { key }
+            }
         };
         Ok(event)
     }
@@ -205,9 +422,14 @@ where
     pub fn next_timeout(
         &mut self,
         timeout: Duration,
-    ) -> Result<Event<V>, SubscriberTimeoutError<Enc::Error>> {
+    ) -> Result<Event<K, V>, SubscriberTimeoutError<Enc::Error>> {
         let orig = self.sub.next_timeout(timeout)?;
-        let event = Self::event_from_sled(orig).map_err(SubscriberTimeoutError::Deserialize)?;
+        let event = Self::event_from_sled(orig).map_err(|error| match error {
+            SubscriberIteratorError::Deserialize(error) => {
+                SubscriberTimeoutError::Deserialize(error)
+            }
+            SubscriberIteratorError::Key(error) => SubscriberTimeoutError::Key(error),
+        })?;
         Ok(event)
     }
 }
@@ -216,27 +438,30 @@ where
 pub enum SubscriberIteratorError<DeserializeError: 'static + std::error::Error> {
     #[error("error deserializing: {0}")]
     Deserialize(#[source] DeserializeError),
+
+    #[error("error decoding key: {0}")]
+    Key(#[source] KeyError),
 }
 
-impl<V, Enc: self::encoding::Encoding + 'static> Iterator for Subscriber<V, Enc>
+impl<K, V, Enc: self::encoding::Encoding + 'static> Iterator for Subscriber<K, V, Enc>
 where
+    K: 'static + Key,
     V: 'static + for<'de> serde::de::Deserialize<'de>,
 {
     // TODO no RecvTimeoutError here
-    type Item = Result<Event<V>, SubscriberIteratorError<Enc::Error>>;
+    type Item = Result<Event<K, V>, SubscriberIteratorError<Enc::Error>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.sub
-            .next()
-            .map(|orig| Self::event_from_sled(orig).map_err(SubscriberIteratorError::Deserialize))
+        self.sub.next().map(Self::event_from_sled)
     }
 }
 
-impl<V, Enc: self::encoding::Encoding + 'static> Future for Subscriber<V, Enc>
+impl<K, V, Enc: self::encoding::Encoding + 'static> Future for Subscriber<K, V, Enc>
 where
+    K: 'static + Key,
     V: 'static + for<'de> serde::de::Deserialize<'de>,
 {
-    type Output = Option<Result<Event<V>, SubscriberIteratorError<Enc::Error>>>;
+    type Output = Option<Result<Event<K, V>, SubscriberIteratorError<Enc::Error>>>;
 
     fn poll(
         self: Pin<&mut Self>,
@@ -248,10 +473,59 @@ where
             std::task::Poll::Pending => std::task::Poll::Pending,
             std::task::Poll::Ready(None) => std::task::Poll::Ready(None),
             std::task::Poll::Ready(Some(orig)) => {
-                let result =
-                    Self::event_from_sled(orig).map_err(SubscriberIteratorError::Deserialize);
-                std::task::Poll::Ready(Some(result))
+                std::task::Poll::Ready(Some(Self::event_from_sled(orig)))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Bincode;
+
+    type Numbers = Tree<u32, Vec<u8>, (), Bincode>;
+
+    impl Merge<()> for Vec<u8> {
+        fn merge(&mut self, _update: ()) -> MergeVerdict {
+            MergeVerdict::Keep
+        }
+    }
+
+    fn numbers() -> Numbers {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Numbers::new(db.open_tree("numbers").unwrap());
+        for n in [1, 2, 3, 5, 8] {
+            tree.insert(&n, &vec![]).unwrap();
+        }
+        tree
+    }
+
+    fn keys(iter: Iter<u32, Vec<u8>, Bincode>) -> Vec<u32> {
+        iter.map(|result| result.unwrap().0).collect()
+    }
+
+    #[test]
+    fn range() {
+        let tree = numbers();
+        assert_eq!(keys(tree.range(2..5)), vec![2, 3]);
+        assert_eq!(keys(tree.range(3..)), vec![3, 5, 8]);
+        assert_eq!(keys(tree.range(..=3)), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reverse() {
+        let tree = numbers();
+        let got: Vec<u32> = tree.iter().rev().map(|r| r.unwrap().0).collect();
+        assert_eq!(got, vec![8, 5, 3, 2, 1]);
+        let got: Vec<u32> = tree.range(2..=5).rev().map(|r| r.unwrap().0).collect();
+        assert_eq!(got, vec![5, 3, 2]);
+    }
+
+    #[test]
+    fn first_last() {
+        let tree = numbers();
+        assert_eq!(tree.first().unwrap().map(|(k, _)| k), Some(1));
+        assert_eq!(tree.last().unwrap().map(|(k, _)| k), Some(8));
+    }
+}
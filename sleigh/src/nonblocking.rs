@@ -0,0 +1,198 @@
+//! A `Tree` for async code, which does the blocking work on tokio's blocking threads.
+//!
+//! sled blocks on disk I/O, which must not happen on the threads running async tasks.
+
+use crate::{
+    encoding::Encoding, key, Batch, GetError, InsertError, Iter, Key, Merge, Prefix, Tree,
+};
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// How many items a scan reads ahead of its consumer.
+const SCAN_BUFFER: usize = 64;
+
+pub struct AsyncTree<K, V, U, Enc: Encoding> {
+    tree: Arc<Tree<K, V, U, Enc>>,
+}
+
+// RUST-WART derive(Clone) would want all the type parameters to be Clone.
+impl<K, V, U, Enc: Encoding> Clone for AsyncTree<K, V, U, Enc> {
+    fn clone(&self) -> Self {
+        AsyncTree {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+// Run `f` on a blocking thread, passing on its panics.
+async fn blocking<F, R>(f: F) -> R
+where
+    F: 'static + Send + FnOnce() -> R,
+    R: 'static + Send,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(error) => match error.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            // Only happens when the runtime is shutting down.
+            Err(error) => panic!("database task did not finish: {}", error),
+        },
+    }
+}
+
+impl<K, V, U, Enc> AsyncTree<K, V, U, Enc>
+where
+    K: 'static + Key + Send + Sync,
+    V: 'static
+        + serde::Serialize
+        + for<'de> serde::de::Deserialize<'de>
+        + Default
+        + Merge<U>
+        + Send
+        + Sync,
+    U: 'static + serde::Serialize + for<'de> serde::de::Deserialize<'de> + Send + Sync,
+    Enc: 'static + Encoding + Send + Sync,
+    Enc::Error: Send,
+{
+    pub fn new(tree: Tree<K, V, U, Enc>) -> Self {
+        AsyncTree {
+            tree: Arc::new(tree),
+        }
+    }
+
+    /// The blocking API, for threads that are not running async tasks.
+    pub fn blocking(&self) -> &Tree<K, V, U, Enc> {
+        &self.tree
+    }
+
+    pub async fn get(&self, key: K) -> Result<Option<V>, GetError<Enc::Error>> {
+        let tree = self.tree.clone();
+        blocking(move || tree.get(&key)).await
+    }
+
+    pub async fn insert(&self, key: K, item: V) -> Result<(), InsertError<Enc::Error>> {
+        let tree = self.tree.clone();
+        blocking(move || tree.insert(&key, &item)).await
+    }
+
+    pub async fn merge(&self, key: K, update: U) -> Result<(), InsertError<Enc::Error>> {
+        let tree = self.tree.clone();
+        blocking(move || tree.merge(&key, &update)).await
+    }
+
+    pub async fn apply_batch(
+        &self,
+        batch: Batch<K, V, U, Enc>,
+    ) -> Result<(), InsertError<Enc::Error>> {
+        let tree = self.tree.clone();
+        blocking(move || tree.apply_batch(batch)).await
+    }
+
+    pub async fn flush(&self) -> Result<usize, sled::Error> {
+        self.tree.tree.flush_async().await
+    }
+
+    pub fn iter(&self) -> Scan<K, V, Enc> {
+        self.scan(|tree| tree.iter())
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Scan<K, V, Enc> {
+        let range = key::encode_range(&range);
+        self.scan(move |tree| tree.range(range))
+    }
+
+    pub fn scan_prefix<P>(&self, prefix: &P) -> Scan<K, V, Enc>
+    where
+        P: ?Sized + Prefix<K>,
+    {
+        let prefix = key::encode_prefix(prefix);
+        self.scan(move |tree| tree.scan_prefix(prefix))
+    }
+
+    fn scan<F>(&self, f: F) -> Scan<K, V, Enc>
+    where
+        F: 'static + Send + FnOnce(&sled::Tree) -> sled::Iter,
+    {
+        let (sender, receiver) = tokio::sync::mpsc::channel(SCAN_BUFFER);
+        let tree = self.tree.clone();
+        // Not awaited; the scan ends early when the `Scan` is dropped.
+        let _ignore_handle = tokio::task::spawn_blocking(move || {
+            let iter: Iter<K, V, Enc> = Iter::new(f(&tree.tree));
+            for result in iter {
+                if sender.blocking_send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        Scan { receiver }
+    }
+}
+
+type ScanItem<K, V, Enc> = Result<(K, V), GetError<<Enc as Encoding>::Error>>;
+
+/// A `Stream` of the items of an `AsyncTree`, in key order.
+pub struct Scan<K, V, Enc: 'static + Encoding> {
+    receiver: tokio::sync::mpsc::Receiver<ScanItem<K, V, Enc>>,
+}
+
+impl<K, V, Enc: 'static + Encoding> futures_core::Stream for Scan<K, V, Enc> {
+    type Item = ScanItem<K, V, Enc>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::Bincode, MergeVerdict};
+    use futures_util::{StreamExt, TryStreamExt};
+
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, PartialEq)]
+    struct Counter {
+        n: u64,
+    }
+
+    impl Merge<u64> for Counter {
+        fn merge(&mut self, add: u64) -> MergeVerdict {
+            self.n += add;
+            MergeVerdict::Keep
+        }
+    }
+
+    type Counters = AsyncTree<u32, Counter, u64, Bincode>;
+
+    fn counters() -> Counters {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        AsyncTree::new(Tree::new(db.open_tree("counters").unwrap()))
+    }
+
+    #[tokio::test]
+    async fn get_merge() {
+        let tree = counters();
+        assert_eq!(tree.get(1).await.unwrap(), None);
+        tree.merge(1, 3).await.unwrap();
+        tree.merge(1, 4).await.unwrap();
+        assert_eq!(tree.get(1).await.unwrap(), Some(Counter { n: 7 }));
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let tree = counters();
+        // More than fits in the buffer.
+        for n in 0..200 {
+            tree.insert(n, Counter { n: n.into() }).await.unwrap();
+        }
+        let keys: Vec<u32> = tree
+            .range(10..)
+            .map_ok(|(key, _item)| key)
+            .take(100)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(keys, (10..110).collect::<Vec<u32>>());
+    }
+}
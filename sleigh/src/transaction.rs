@@ -0,0 +1,468 @@
+//! Batches and transactions, typed like `Tree`.
+//!
+//! sled has no merge inside transactions or batches.
+//! `TransactionalTree::merge`, and so `Batch::merge`, instead read the item, apply `Merge` and write the result, which gives the same outcome.
+
+use crate::{
+    corruption, encoding::Encoding, index, key, CorruptionKind, CorruptionPolicy, InsertError, Key,
+    Merge, MergeVerdict, Tree,
+};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, Transactional, UnabortableTransactionError,
+};
+
+/// Writes to apply to a `Tree` atomically, with `Tree::apply_batch`.
+pub struct Batch<K, V, U, Enc: Encoding> {
+    // Encoded keys, in the order written.
+    writes: Vec<(Vec<u8>, Write)>,
+    _phantom_k: std::marker::PhantomData<K>,
+    _phantom_v: std::marker::PhantomData<V>,
+    _phantom_u: std::marker::PhantomData<U>,
+    _phantom_enc: std::marker::PhantomData<Enc>,
+}
+
+enum Write {
+    Insert(Vec<u8>),
+    Remove,
+    // An encoded update.
+    Merge(Vec<u8>),
+}
+
+impl<K, V, U, Enc: Encoding> Default for Batch<K, V, U, Enc> {
+    fn default() -> Self {
+        Batch {
+            writes: Vec::new(),
+            _phantom_k: std::marker::PhantomData,
+            _phantom_v: std::marker::PhantomData,
+            _phantom_u: std::marker::PhantomData,
+            _phantom_enc: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K, V, U, Enc> Batch<K, V, U, Enc>
+where
+    K: Key,
+    V: serde::Serialize,
+    U: serde::Serialize,
+    Enc: Encoding,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: &K, item: &V) -> Result<(), Enc::Error> {
+        let buf = Enc::serialize(item)?;
+        self.writes.push((key::encode(key), Write::Insert(buf)));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        self.writes.push((key::encode(key), Write::Remove));
+    }
+
+    /// Like `Tree::merge`.
+    /// A batch with merges is applied as a transaction, as sled batches cannot merge.
+    pub fn merge(&mut self, key: &K, update: &U) -> Result<(), Enc::Error> {
+        let buf = Enc::serialize(update)?;
+        self.writes.push((key::encode(key), Write::Merge(buf)));
+        Ok(())
+    }
+
+    fn has_merges(&self) -> bool {
+        self.writes
+            .iter()
+            .any(|(_, write)| matches!(write, Write::Merge(_)))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TxError<E, EncodingError: 'static + std::error::Error> {
+    /// Returned by the transaction closure to abort the transaction.
+    #[error("transaction aborted")]
+    Abort(E),
+
+    #[error("error serializing: {0}")]
+    Serialize(#[source] EncodingError),
+
+    #[error("error deserializing: {0}")]
+    Deserialize(#[source] EncodingError),
+
+    /// Only seen inside the transaction closure, which must pass it on; sled then retries.
+    #[doc(hidden)]
+    #[error("conflict during transaction")]
+    Conflict,
+
+    #[error("database error: {0}")]
+    DB(#[source] sled::Error),
+}
+
+impl<E, EncodingError: 'static + std::error::Error> From<UnabortableTransactionError>
+    for TxError<E, EncodingError>
+{
+    fn from(error: UnabortableTransactionError) -> Self {
+        match error {
+            UnabortableTransactionError::Conflict => TxError::Conflict,
+            UnabortableTransactionError::Storage(error) => TxError::DB(error),
+        }
+    }
+}
+
+impl<E, EncodingError: 'static + std::error::Error> TxError<E, EncodingError> {
+    fn into_sled(self) -> ConflictableTransactionError<Self> {
+        match self {
+            TxError::Conflict => ConflictableTransactionError::Conflict,
+            TxError::DB(error) => ConflictableTransactionError::Storage(error),
+            other => ConflictableTransactionError::Abort(other),
+        }
+    }
+
+    fn from_sled(error: TransactionError<Self>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => TxError::DB(error),
+        }
+    }
+}
+
+/// A `Tree` inside a transaction.
+pub struct TransactionalTree<'a, K, V, U, Enc, E> {
+    tree: &'a sled::transaction::TransactionalTree,
+    indexes: Vec<(&'a sled::transaction::TransactionalTree, &'a index::Hook<V>)>,
+    // Only for `Tree::merge`; transactions of their own fail instead.
+    corruption: Option<&'a corruption::Handler>,
+    _phantom: std::marker::PhantomData<(K, U, Enc, E)>,
+}
+
+impl<'a, K, V, U, Enc, E> TransactionalTree<'a, K, V, U, Enc, E>
+where
+    K: Key,
+    V: serde::Serialize + for<'de> serde::de::Deserialize<'de> + Default + Merge<U>,
+    Enc: 'static + Encoding,
+{
+    fn new(
+        tree: &'a sled::transaction::TransactionalTree,
+        indexes: &'a [sled::transaction::TransactionalTree],
+        hooks: &'a [index::Hook<V>],
+        corruption: Option<&'a corruption::Handler>,
+    ) -> Self {
+        TransactionalTree {
+            tree,
+            indexes: indexes.iter().zip(hooks).collect(),
+            corruption,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    // Every write goes through here, to keep the indexes in sync.
+    fn write(&self, key: &[u8], new: Option<&[u8]>) -> Result<(), TxError<E, Enc::Error>> {
+        if !self.indexes.is_empty() {
+            // The entries of a corrupt old item can't be known, and stay.
+            let old: Option<V> = match self.tree.get(key)? {
+                None => None,
+                Some(buf) => Enc::deserialize(&buf).ok(),
+            };
+            let new: Option<V> = new.and_then(|buf| Enc::deserialize(buf).ok());
+            for (tree, hook) in &self.indexes {
+                let old_entry = old.as_ref().and_then(|item| hook.entry(key, item));
+                let new_entry = new.as_ref().and_then(|item| hook.entry(key, item));
+                if old_entry != new_entry {
+                    if let Some(entry) = old_entry {
+                        let _ = tree.remove(entry)?;
+                    }
+                    if let Some(entry) = new_entry {
+                        let _ = tree.insert(entry, &[])?;
+                    }
+                }
+            }
+        }
+        let _ = match new {
+            Some(buf) => self.tree.insert(key, buf)?,
+            None => self.tree.remove(key)?,
+        };
+        Ok(())
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, TxError<E, Enc::Error>> {
+        match self.tree.get(key::encode(key))? {
+            None => Ok(None),
+            Some(buf) => {
+                let item: V = Enc::deserialize(&buf).map_err(TxError::Deserialize)?;
+                Ok(Some(item))
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &K, item: &V) -> Result<(), TxError<E, Enc::Error>> {
+        let buf = Enc::serialize(item).map_err(TxError::Serialize)?;
+        self.write(&key::encode(key), Some(&buf))
+    }
+
+    pub fn remove(&self, key: &K) -> Result<(), TxError<E, Enc::Error>> {
+        self.write(&key::encode(key), None)
+    }
+
+    /// Like `Tree::merge`.
+    /// A corrupt item fails the transaction, instead of going through the corruption policy.
+    pub fn merge(&self, key: &K, update: U) -> Result<(), TxError<E, Enc::Error>> {
+        self.merge_encoded(&key::encode(key), update)
+    }
+
+    fn merge_encoded(&self, key: &[u8], update: U) -> Result<(), TxError<E, Enc::Error>> {
+        let mut item = match self.tree.get(key)? {
+            None => V::default(),
+            Some(buf) => match Enc::deserialize::<V>(&buf) {
+                Ok(item) => item,
+                Err(error) => match self.corruption {
+                    None => return Err(TxError::Deserialize(error)),
+                    Some(corruption) => {
+                        corruption.report(key, CorruptionKind::Stored, &error, Some(&buf));
+                        match corruption.policy {
+                            CorruptionPolicy::KeepOld => return Ok(()),
+                            // Panic already happened in report.
+                            _ => V::default(),
+                        }
+                    }
+                },
+            },
+        };
+        match item.merge(update) {
+            MergeVerdict::Keep => {
+                let buf = Enc::serialize(&item).map_err(TxError::Serialize)?;
+                self.write(key, Some(&buf))
+            }
+            MergeVerdict::Remove => self.write(key, None),
+        }
+    }
+
+    pub fn apply_batch(&self, batch: &Batch<K, V, U, Enc>) -> Result<(), TxError<E, Enc::Error>>
+    where
+        U: for<'de> serde::Deserialize<'de>,
+    {
+        for (key, write) in &batch.writes {
+            match write {
+                Write::Insert(buf) => self.write(key, Some(buf))?,
+                Write::Remove => self.write(key, None)?,
+                Write::Merge(buf) => {
+                    let update: U = Enc::deserialize(buf).map_err(TxError::Deserialize)?;
+                    self.merge_encoded(key, update)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, U, Enc> Tree<K, V, U, Enc>
+where
+    K: 'static + Key,
+    V: 'static + serde::Serialize + for<'de> serde::de::Deserialize<'de> + Default + Merge<U>,
+    U: 'static + serde::Serialize + for<'de> serde::de::Deserialize<'de>,
+    Enc: 'static + Encoding,
+{
+    pub fn apply_batch(&self, batch: Batch<K, V, U, Enc>) -> Result<(), InsertError<Enc::Error>> {
+        if !self.indexes.is_empty() || batch.has_merges() {
+            return self.transactional_write(|tx| tx.apply_batch(&batch));
+        }
+        let mut sled_batch = sled::Batch::default();
+        for (key, write) in batch.writes {
+            match write {
+                Write::Insert(buf) => sled_batch.insert(key, buf),
+                Write::Remove => sled_batch.remove(key),
+                Write::Merge(_) => unreachable!("internal error: merges need a transaction"),
+            }
+        }
+        self.tree.apply_batch(sled_batch)?;
+        Ok(())
+    }
+
+    // For writes sled cannot do on its own: any write with indexes, and batches with merges.
+    pub(crate) fn transactional_write<F>(&self, f: F) -> Result<(), InsertError<Enc::Error>>
+    where
+        F: Fn(
+            &TransactionalTree<'_, K, V, U, Enc, std::convert::Infallible>,
+        ) -> Result<(), TxError<std::convert::Infallible, Enc::Error>>,
+    {
+        self.run_transaction(Some(&self.corruption), f)
+            .map_err(|error| match error {
+                TxError::Serialize(error) => InsertError::Serialize(error),
+                TxError::DB(error) => InsertError::DB(error),
+                TxError::Abort(never) => match never {},
+                TxError::Deserialize(_) | TxError::Conflict => {
+                    unreachable!("internal error: writes cannot fail like that")
+                }
+            })
+    }
+
+    fn run_transaction<F, A, E>(
+        &self,
+        corruption: Option<&corruption::Handler>,
+        f: F,
+    ) -> Result<A, TxError<E, Enc::Error>>
+    where
+        F: Fn(&TransactionalTree<'_, K, V, U, Enc, E>) -> Result<A, TxError<E, Enc::Error>>,
+    {
+        let mut trees = vec![&self.tree];
+        trees.extend(self.indexes.iter().map(|hook| &hook.tree));
+        trees[..]
+            .transaction(|views| {
+                let tx = TransactionalTree::new(&views[0], &views[1..], &self.indexes, corruption);
+                f(&tx).map_err(TxError::into_sled)
+            })
+            .map_err(TxError::from_sled)
+    }
+
+    /// Run `f` in a transaction.
+    /// `f` may run several times, if it conflicts with concurrent writes.
+    /// Merges done outside of transactions do not conflict with it, unless the tree has indexes.
+    pub fn transaction<F, A, E>(&self, f: F) -> Result<A, TxError<E, Enc::Error>>
+    where
+        F: Fn(&TransactionalTree<'_, K, V, U, Enc, E>) -> Result<A, TxError<E, Enc::Error>>,
+    {
+        self.run_transaction(None, f)
+    }
+
+    /// Run `f` in a transaction spanning this and another tree.
+    pub fn transaction_with<K2, V2, U2, F, A, E>(
+        &self,
+        other: &Tree<K2, V2, U2, Enc>,
+        f: F,
+    ) -> Result<A, TxError<E, Enc::Error>>
+    where
+        K2: Key,
+        V2: serde::Serialize + for<'de> serde::de::Deserialize<'de> + Default + Merge<U2>,
+        F: Fn(
+            &TransactionalTree<'_, K, V, U, Enc, E>,
+            &TransactionalTree<'_, K2, V2, U2, Enc, E>,
+        ) -> Result<A, TxError<E, Enc::Error>>,
+    {
+        let mut trees = vec![&self.tree];
+        trees.extend(self.indexes.iter().map(|hook| &hook.tree));
+        trees.push(&other.tree);
+        trees.extend(other.indexes.iter().map(|hook| &hook.tree));
+        let other_start = 1 + self.indexes.len();
+        trees[..]
+            .transaction(|views| {
+                let (ours, theirs) = views.split_at(other_start);
+                f(
+                    &TransactionalTree::new(&ours[0], &ours[1..], &self.indexes, None),
+                    &TransactionalTree::new(&theirs[0], &theirs[1..], &other.indexes, None),
+                )
+                .map_err(TxError::into_sled)
+            })
+            .map_err(TxError::from_sled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Bincode;
+
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, PartialEq)]
+    struct Counter {
+        n: u64,
+    }
+
+    impl Merge<u64> for Counter {
+        fn merge(&mut self, add: u64) -> MergeVerdict {
+            if add == 0 {
+                return MergeVerdict::Remove;
+            }
+            self.n += add;
+            MergeVerdict::Keep
+        }
+    }
+
+    type CounterTree = Tree<String, Counter, u64, Bincode>;
+
+    fn open(name: &str) -> CounterTree {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        CounterTree::new(db.open_tree(name).unwrap())
+    }
+
+    #[test]
+    fn batch() {
+        let tree = open("counters");
+        tree.insert(&"gone".to_string(), &Counter { n: 1 }).unwrap();
+        let mut batch = Batch::new();
+        batch.insert(&"a".to_string(), &Counter { n: 1 }).unwrap();
+        batch.insert(&"b".to_string(), &Counter { n: 2 }).unwrap();
+        batch.remove(&"gone".to_string());
+        tree.apply_batch(batch).unwrap();
+        let all: Vec<(String, Counter)> = tree.iter().map(Result::unwrap).collect();
+        assert_eq!(
+            all,
+            vec![
+                ("a".to_string(), Counter { n: 1 }),
+                ("b".to_string(), Counter { n: 2 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_merge() {
+        let tree = open("counters");
+        tree.insert(&"a".to_string(), &Counter { n: 1 }).unwrap();
+        let mut batch = Batch::new();
+        batch.merge(&"a".to_string(), &2).unwrap();
+        batch.merge(&"b".to_string(), &3).unwrap();
+        batch.insert(&"c".to_string(), &Counter { n: 4 }).unwrap();
+        batch.merge(&"c".to_string(), &5).unwrap();
+        tree.apply_batch(batch).unwrap();
+        let all: Vec<(String, Counter)> = tree.iter().map(Result::unwrap).collect();
+        assert_eq!(
+            all,
+            vec![
+                ("a".to_string(), Counter { n: 3 }),
+                ("b".to_string(), Counter { n: 3 }),
+                ("c".to_string(), Counter { n: 9 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn transaction_merge() {
+        let tree = open("counters");
+        tree.transaction(|tx| {
+            tx.merge(&"a".to_string(), 2)?;
+            tx.merge(&"a".to_string(), 3)?;
+            tx.merge(&"b".to_string(), 1)?;
+            tx.merge(&"b".to_string(), 0)?;
+            Ok::<_, TxError<(), _>>(())
+        })
+        .unwrap();
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), Some(Counter { n: 5 }));
+        assert_eq!(tree.get(&"b".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_abort() {
+        let tree = open("counters");
+        let result = tree.transaction(|tx| {
+            tx.merge(&"a".to_string(), 2)?;
+            Err::<(), _>(TxError::Abort("nope"))
+        });
+        assert!(matches!(result, Err(TxError::Abort("nope"))));
+        assert_eq!(tree.get(&"a".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_with() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let from = CounterTree::new(db.open_tree("from").unwrap());
+        let to = CounterTree::new(db.open_tree("to").unwrap());
+        from.insert(&"a".to_string(), &Counter { n: 7 }).unwrap();
+        from.transaction_with(&to, |from, to| {
+            let key = "a".to_string();
+            if let Some(item) = from.get(&key)? {
+                from.remove(&key)?;
+                to.insert(&key, &item)?;
+            }
+            Ok::<_, TxError<(), _>>(())
+        })
+        .unwrap();
+        assert_eq!(from.get(&"a".to_string()).unwrap(), None);
+        assert_eq!(to.get(&"a".to_string()).unwrap(), Some(Counter { n: 7 }));
+    }
+}
@@ -0,0 +1,231 @@
+//! Values that carry their schema version on the wire, and upgrade old versions on read.
+//!
+//! To add a field to a `versioned!` type:
+//!
+//! - copy its current fields into a new struct, e.g. `MediaV2`, deriving `serde::Deserialize`
+//! - add that struct to `upgrade from`, and implement `From` from it to the next version
+//! - bump the version in the header, e.g. `MediaVersioned::V3`, and edit the fields
+//!
+//! Never remove or reorder versions, or the tag on the wire goes out of sync.
+
+/// One step in the upgrade chain of a `versioned!` type; implemented by the macro.
+pub trait Upgrade<Latest> {
+    fn upgrade(self) -> Latest;
+}
+
+/// Define a struct that is serialized inside a version envelope.
+///
+/// ```ignore
+/// sleigh::versioned! {
+///     #[derive(Debug, Default)]
+///     pub struct Media: MediaVersioned::V2 {
+///         pub exists: bool,
+///         pub watched: bool,
+///     }
+///     // Oldest first. Each must implement `From` into the next one, and the last into `Media`.
+///     upgrade from {
+///         V1(MediaV1),
+///     }
+/// }
+/// ```
+///
+/// The caller needs `serde` with the `derive` feature.
+#[macro_export]
+macro_rules! versioned {
+    (@upgrade $name:ident;) => {};
+    (@upgrade $name:ident; $old:ty, $next:ty $(, $rest:ty)*) => {
+        impl $crate::Upgrade<$name> for $old {
+            fn upgrade(self) -> $name {
+                let next: $next = ::std::convert::From::from(self);
+                $crate::Upgrade::upgrade(next)
+            }
+        }
+        $crate::versioned!(@upgrade $name; $next $(, $rest)*);
+    };
+    (@upgrade $name:ident; $last:ty) => {
+        impl $crate::Upgrade<$name> for $last {
+            fn upgrade(self) -> $name {
+                ::std::convert::From::from(self)
+            }
+        }
+    };
+
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident : $envelope:ident :: $variant:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $field_ty:ty
+            ),* $(,)?
+        }
+        upgrade from {
+            $($old_variant:ident($old:ty)),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $field_ty,
+            )*
+        }
+
+        #[derive(serde::Deserialize)]
+        enum $envelope {
+            $($old_variant($old),)*
+            $variant { $($field: $field_ty,)* },
+        }
+
+        $crate::versioned!(@upgrade $name; $($old),*);
+
+        impl ::std::convert::From<$envelope> for $name {
+            fn from(envelope: $envelope) -> Self {
+                match envelope {
+                    $($envelope::$old_variant(old) => $crate::Upgrade::upgrade(old),)*
+                    $envelope::$variant { $($field,)* } => $name { $($field,)* },
+                }
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                let envelope = <$envelope as serde::Deserialize>::deserialize(deserializer)?;
+                Ok(::std::convert::From::from(envelope))
+            }
+        }
+
+        const _: () = {
+            // Same variants as the envelope, for serializing the latest version from a reference.
+            // Old versions are never written.
+            #[derive(serde::Serialize)]
+            #[allow(dead_code)]
+            enum Ref<'a> {
+                $($old_variant(::std::marker::PhantomData<$old>),)*
+                $variant { $($field: &'a $field_ty,)* },
+            }
+
+            impl serde::Serialize for $name {
+                fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where
+                    S: serde::Serializer,
+                {
+                    let latest = Ref::$variant {
+                        $($field: &self.$field,)*
+                    };
+                    serde::Serialize::serialize(&latest, serializer)
+                }
+            }
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{encoding::Bincode, encoding::Encoding, Merge, MergeVerdict, Tree};
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    enum OldEnvelope {
+        V1 { name: String },
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ThingV1 {
+        name: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ThingV2 {
+        name: String,
+        size: u32,
+    }
+
+    impl From<ThingV1> for ThingV2 {
+        fn from(old: ThingV1) -> Self {
+            ThingV2 {
+                name: old.name,
+                size: 0,
+            }
+        }
+    }
+
+    impl From<ThingV2> for Thing {
+        fn from(old: ThingV2) -> Self {
+            Thing {
+                name: old.name,
+                size: old.size.into(),
+                tags: Vec::new(),
+            }
+        }
+    }
+
+    crate::versioned! {
+        #[derive(Debug, Default, PartialEq)]
+        pub struct Thing: ThingVersioned::V3 {
+            pub name: String,
+            /// Now bigger.
+            pub size: u64,
+            pub tags: Vec<String>,
+        }
+        upgrade from {
+            V1(ThingV1),
+            V2(ThingV2),
+        }
+    }
+
+    impl Merge<()> for Thing {
+        fn merge(&mut self, _update: ()) -> MergeVerdict {
+            MergeVerdict::Keep
+        }
+    }
+
+    #[test]
+    fn upgrade_chain() {
+        let old = <Bincode>::serialize(&OldEnvelope::V1 {
+            name: "foo".to_string(),
+        })
+        .unwrap();
+        let thing: Thing = <Bincode>::deserialize(&old).unwrap();
+        assert_eq!(
+            thing,
+            Thing {
+                name: "foo".to_string(),
+                size: 0,
+                tags: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn roundtrip_latest() {
+        let thing = Thing {
+            name: "foo".to_string(),
+            size: 42,
+            tags: vec!["bar".to_string()],
+        };
+        let buf = <Bincode>::serialize(&thing).unwrap();
+        // The tag of `V3`.
+        assert_eq!(buf[0], 2);
+        let got: Thing = <Bincode>::deserialize(&buf).unwrap();
+        assert_eq!(got, thing);
+    }
+
+    #[test]
+    fn rewrite_all() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let raw = db.open_tree("things").unwrap();
+        let tree: Tree<String, Thing, (), Bincode> = Tree::new(raw.clone());
+        let old = <Bincode>::serialize(&OldEnvelope::V1 {
+            name: "foo".to_string(),
+        })
+        .unwrap();
+        raw.insert("old", old).unwrap();
+        tree.insert(&"new".to_string(), &Thing::default()).unwrap();
+
+        assert_eq!(tree.rewrite_all().unwrap(), 1);
+        assert_eq!(raw.get("old").unwrap().unwrap()[0], 2);
+        assert_eq!(tree.rewrite_all().unwrap(), 0);
+    }
+}